@@ -1,31 +1,96 @@
 //Imports
 //Tokio - used for network stuff
 use tokio::{
-    io::{self, AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
+    io::{self, ReadHalf, WriteHalf},
+    net::TcpStream,
     sync::mpsc,
+    time::{sleep, timeout},
 };
-//Serde - used for serializing (turning into bytes) and deserializing messages
-use serde::{Serialize, Deserialize};
+//StructOpt - used for getting input from the command line
+use structopt::StructOpt;
+//Length-prefixed framing and the cluster configuration, shared with the nodes
+use omnipaxos_key_value_store::{
+    framing::{read_frame, write_frame, DEFAULT_MAX_FRAME_SIZE},
+    config::{ClusterConfig, NodeAddress},
+    words::{quote, split_words},
+    encoding::{from_base64, from_hex, to_hex},
+};
+//HashMap - used for the open connections to the nodes, PathBuf for the configuration file, Duration for the retry settings
+use std::{collections::{HashMap, hash_map::Entry}, path::PathBuf, time::Duration};
+
+//The commands the client understands, and the number of words (including the command itself) each of them needs
+//add-node and remove-node are sent to the node their pid maps to, the same way as a key; status is sent to the node with the pid given
+//A txn is a list of guards and writes, e.g. txn if a == 5 put a 4 put b 6 - the smallest is a single delete
+const COMMANDS: [&str; 20] = ["get", "put", "delete", "cas", "put-if-absent", "incr", "decr", "lpush", "rpush", "lpop", "rpop", "sadd", "srem", "smembers", "hset", "hget", "txn", "add-node", "remove-node", "status"];
+fn required_words(command: &str) -> usize {
+    match command {
+        "get" | "delete" | "lpop" | "rpop" | "smembers" | "remove-node" | "status" => 2,
+        "put" | "put-if-absent" | "incr" | "decr" | "lpush" | "rpush" | "sadd" | "srem" | "hget" | "txn" | "add-node" => 3,
+        "cas" | "hset" => 4,
+        _ => 1,
+    }
+}
+
+//Writes are sent straight to the leader once the client knows who it is; the others are sent to the node the key maps to
+const WRITES: [&str; 14] = ["put", "delete", "cas", "put-if-absent", "incr", "decr", "lpush", "rpush", "lpop", "rpop", "sadd", "srem", "hset", "txn"];
+//How many times a write is redirected to another leader before the client lets a node forward it instead
+const MAX_REDIRECTS: u32 = 3;
+//How many requests back a redirected write may have been sent
+const REDIRECT_WINDOW: u64 = 1000;
+
+//Command line options of the client
+#[derive(Debug, StructOpt)]
+struct Client {
+    //Cluster configuration file; without it the nodes are assumed to be on 127.0.0.1 at the default ports
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+    //How many other nodes to try when a node cannot be reached, how long to wait for a connection (in milliseconds)
+    //and how long to wait before the first retry (in milliseconds, doubling for every further retry)
+    #[structopt(long, default_value = "3")]
+    retries: u32,
+    #[structopt(long, default_value = "1000")]
+    timeout_ms: u64,
+    #[structopt(long, default_value = "100")]
+    backoff_ms: u64,
+    //Largest response (in bytes) accepted from a node - framing::DEFAULT_MAX_FRAME_SIZE (16 MiB) by default, the same as the nodes
+    #[structopt(long)]
+    max_frame_size: Option<usize>,
+}
+
+//The Failover struct holds the retry settings, and the pids of the nodes to fail over to (none if there are none to fail over to)
+//The largest response accepted on the connections it opens is kept with them
+struct Failover {
+    retries: u32,
+    timeout: Duration,
+    backoff: Duration,
+    members: Vec<u64>,
+    max_frame_size: usize,
+}
 
 #[tokio::main]
 async fn main() {
+    let options = Client::from_args();
+    let failover = Failover {
+        retries: options.retries,
+        timeout: Duration::from_millis(options.timeout_ms),
+        backoff: Duration::from_millis(options.backoff_ms),
+        members: vec![],
+        max_frame_size: options.max_frame_size.unwrap_or(DEFAULT_MAX_FRAME_SIZE),
+    };
+    let cluster = options.config.map(|path| ClusterConfig::load(&path).unwrap_or_else(|error| panic!(" -> ERROR: Bad cluster configuration - {}", error)));
+
     //Create mpsc channels for communication
-    //Receiver will handle incoming messages, sender_peers will send peers messages and sender_messages will send other messages
-    let (sender_peers, receiver) = mpsc::channel(32);
-    let sender_messages = sender_peers.clone();
+    //Receiver will handle incoming messages, sender_responses will send on what the nodes report and sender_messages will send the commands
+    let (sender_responses, receiver) = mpsc::channel(32);
+    let sender_messages = sender_responses.clone();
 
     //Spawn threads
     tokio::spawn(async move {
-        get_peers(sender_peers).await;
-    }); 
-    tokio::spawn(async move {
-        give_results().await;
-    });
-    tokio::spawn(async move {
-        message_receiver(receiver).await;
+        message_receiver(receiver, sender_responses, cluster, failover).await;
     });
-    
+    //Print that the client is read to take commands
+    println!("Ready for operations");
+
     //Std:io is required for the read_line method; needs to be imported here in order to not conflict with Tokio
     use std::io;
     //Loop through and read input from the command line of the client
@@ -33,135 +98,462 @@ async fn main() {
         //Get the input command
         let mut input = String::new();
         io::stdin().read_line(&mut input).expect(" -> ERROR: Could not read the input");
-        //Vectorize the input - check the first word to determine if it is a get or a put message
+        //Vectorize the input - check the first word to determine which command it is
         let input_vector:Vec<&str> = input.split(" ").collect();
-        if input_vector[0] == "get" {sender_messages.send(("get", bincode::serialize(&input).unwrap())).await.unwrap();}
-        else if input_vector[0] == "put" {sender_messages.send(("put", bincode::serialize(&input).unwrap())).await.unwrap();}
-        else{
-            //If it is not a put or a get
-            println!(" -> ERROR: Unknown command");
+        match COMMANDS.iter().find(|command| **command == input_vector[0].trim()) {
+            Some(command) => sender_messages.send((command, bincode::serialize(&input).unwrap())).await.unwrap(),
+            //If it is not one of the supported commands
+            None => println!(" -> ERROR: Unknown command"),
         }
     }
 }
 
-//The get_peers functions receives messages from the nodes on their number of peers and sends the information
-//to the primary message-handling function of the client
-async fn get_peers(sender: mpsc::Sender<(&str, Vec<u8>)>) {
-    //Listen on the set "peers" address
-    let address = TcpListener::bind("127.0.0.1:64000").await.unwrap();
-
-    //Loop through received messages on the address
-    loop{
-        //Establish connection properly
-        let (connection, _) = address.accept().await.unwrap();
-        let (mut connection_reader, _) = io::split(connection);
-        let mut buffer = [1; 128];
-
-        loop{
-            let n = connection_reader.read(&mut buffer).await.unwrap();
-            match n {
-                //If n = 0 we have not received any messages
-                0 => break,
-                //If n != 0 we have received a message
-                peers => {
-                    //Deserialize the number of peers
-                    let deserialized_peers: u64 = bincode::deserialize(&buffer[0..peers]).unwrap();
-                    //Send it on to the main message-handling function
-                    sender.send(("peers", bincode::serialize(&(deserialized_peers + 1)).unwrap())).await.unwrap();
-                    break;
+//The give_results function outputs the responses a node sends back on a connection
+//Every response starts with the id of the request it belongs to
+async fn give_results(mut connection_reader: ReadHalf<TcpStream>, sender: mpsc::Sender<(&str, Vec<u8>)>, max_frame_size: usize) {
+    loop {
+        let frame = match read_frame(&mut connection_reader, max_frame_size).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            //Error handling - broken connection or oversized message
+            Err(error) => {
+                println!(" -> ERROR: Could not read result - {}", error);
+                break;
+            },
+        };
+        let return_message: String = match bincode::deserialize(&frame) {
+            Ok(return_message) => return_message,
+            Err(_) => {
+                println!(" -> ERROR: Received a response that could not be read");
+                continue;
+            },
+        };
+        //Split up the message so that its different parts can be examined
+        //Keys with spaces come back in quotes
+        let message_vector: Vec<String> = match split_words(&return_message) {
+            Ok(message_vector) if message_vector.len() >= 2 && message_vector.len() >= response_words(&message_vector[1]) => message_vector,
+            _ => {
+                println!(" -> ERROR: Received an unexpected response: {}", return_message);
+                continue;
+            },
+        };
+        let request_id = &message_vector[0];
+
+        match message_vector[1].as_str() {
+            //The result of a get for a key that exists
+            "value" => println!(" -> Get {}: found key-value pair: key {} value {}", request_id, quote(&message_vector[2]), display_value(&message_vector[3])),
+            //Error handling - if the "get" was for a key that has not been added
+            "not-found" => println!(" -> ERROR: Get {}: key {} not found in database - try searching for a key that exists", request_id, quote(&message_vector[2])),
+            //A write was decided - the message holds the log index it was decided at
+            "ack" => println!(" -> Write {} was decided at log index {}", request_id, message_vector[2]),
+            //A conditional write was decided but its condition did not hold - the message holds the log index and the key's actual value
+            "condition-failed" => {
+                if message_vector[3] == "absent" {
+                    println!(" -> Write {} was decided at log index {} but not applied - the key does not exist", request_id, message_vector[2]);
+                }
+                else {
+                    println!(" -> Write {} was decided at log index {} but not applied - the current value is {}", request_id, message_vector[2], display_value(&message_vector[3]));
+                }
+            },
+            //An increment or decrement was decided - the message holds the log index and the resulting value
+            "counter" => println!(" -> Write {} was decided at log index {} - the value is now {}", request_id, message_vector[2], message_vector[3]),
+            //An increment or decrement was decided but would have gone out of range, so the value was left unchanged
+            "out-of-range" => {
+                if message_vector[3] == "absent" {
+                    println!(" -> Write {} was decided at log index {} but not applied - the key does not exist and cannot go below 0", request_id, message_vector[2]);
+                }
+                else {
+                    println!(" -> Write {} was decided at log index {} but not applied - the value {} would go out of range", request_id, message_vector[2], message_vector[3]);
+                }
+            },
+            //A membership change was decided - the members have changed, so they are asked for again before the next request
+            "reconfigured" => {
+                println!(" -> Request {}: the cluster moved on to configuration {}", request_id, message_vector[2]);
+                sender.send(("nodes", bincode::serialize(&Vec::<u64>::new()).unwrap())).await.unwrap();
+                sender.send(("leader", bincode::serialize(&None::<(u64, u32)>).unwrap())).await.unwrap();
+            },
+            //The status of a node - its pid, configuration, leader, applied and decided index, the index it recovered at and whether it has caught up since
+            "status" => {
+                let leader = if message_vector[4] == "none" { "no leader".to_string() } else { format!("leader {}", message_vector[4]) };
+                let recovery = if message_vector[7] == "fresh" { "started fresh".to_string() } else { format!("recovered at index {} ({})", message_vector[7], message_vector[8]) };
+                println!(" -> Node {}: configuration {}, {}, applied up to index {}, decided up to index {}, {}", message_vector[2], message_vector[3], leader, message_vector[5], message_vector[6], recovery);
+            },
+            //The leader the node follows and its ballot - remembered so that writes can be sent to it directly
+            //A node that knows no leader says so, which leaves the leader the client knows of in place
+            "leader" => {
+                if let Some(leader) = parse_leader(&message_vector[2..]) {
+                    sender.send(("leader", bincode::serialize(&Some(leader)).unwrap())).await.unwrap();
+                }
+            },
+            //A write was sent to a node that is not the leader - the message holds the actual leader and its ballot, if the node knows one
+            "redirect" => match request_id.parse::<u64>() {
+                Ok(request_id) => {
+                    let leader = parse_leader(&message_vector[2..]);
+                    sender.send(("redirect", bincode::serialize(&(request_id, leader)).unwrap())).await.unwrap();
                 },
-            }
+                Err(_) => println!(" -> ERROR: Received an unexpected response: {}", return_message),
+            },
+            //A push was decided - the message holds the log index and the length of the list
+            "length" => println!(" -> Write {} was decided at log index {} - the list now has {} values", request_id, message_vector[2], message_vector[3]),
+            //A pop was decided - the message holds the log index and the value taken off the list
+            "popped" => {
+                if message_vector[3] == "absent" {
+                    println!(" -> Write {} was decided at log index {} - the list is empty", request_id, message_vector[2]);
+                }
+                else {
+                    println!(" -> Write {} was decided at log index {} - popped value {}", request_id, message_vector[2], display_value(&message_vector[3]));
+                }
+            },
+            //The members of a set, in order
+            "members" => {
+                let members: Vec<String> = message_vector[3..].iter().map(|member| display_value(member)).collect();
+                println!(" -> Get {}: set {} has {} members: {}", request_id, quote(&message_vector[2]), members.len(), members.join(" "));
+            },
+            //The key holds another type than the request is for - a write also holds the log index it was decided at
+            "wrong-type" => {
+                if message_vector.len() > 3 {
+                    println!(" -> Write {} was decided at log index {} but not applied - the key holds a {}", request_id, message_vector[2], message_vector[3]);
+                }
+                else {
+                    println!(" -> ERROR: Get {}: the key holds a {}", request_id, message_vector[2]);
+                }
+            },
+            //A transaction was decided - the message holds the log index and whether its guards held, and so whether its writes took effect
+            "txn" => {
+                if message_vector[3] == "passed" {
+                    println!(" -> Transaction {} was decided at log index {} - the guards held and every write was applied", request_id, message_vector[2]);
+                }
+                else {
+                    println!(" -> Transaction {} was decided at log index {} but not applied - a guard did not hold", request_id, message_vector[2]);
+                }
+            },
+            //An increment or decrement of a value that is not a number
+            "not-a-number" => println!(" -> Write {} was decided at log index {} but not applied - the value is not a number", request_id, message_vector[2]),
+            //A request failed - the message holds the reason
+            "error" => println!(" -> ERROR: Request {} failed - {}", request_id, message_vector[2..].join(" ")),
+            //The pids of the members of the node's configuration; send them on to the main message-handling function
+            "nodes" => match message_vector[2..].iter().map(|pid| pid.parse::<u64>()).collect::<Result<Vec<u64>, _>>() {
+                Ok(pids) => sender.send(("nodes", bincode::serialize(&pids).unwrap())).await.unwrap(),
+                Err(_) => println!(" -> ERROR: Received an unexpected response: {}", return_message),
+            },
+            _ => println!(" -> ERROR: Received an unknown response"),
         }
     }
-} 
+}
 
-//The give_results function outputs the result of a "get" operation
-async fn give_results() {
-    //Print that the client is read to take commands
-    println!("Ready for operations");
+//The response_words function gives the number of words (including the request id) each kind of response has at least
+//A shorter response is reported as unexpected rather than read past its end
+fn response_words(response: &str) -> usize {
+    match response {
+        "error" | "nodes" => 2,
+        "not-found" | "ack" | "reconfigured" | "leader" | "redirect" | "members" | "wrong-type" | "not-a-number" => 3,
+        "value" | "condition-failed" | "counter" | "out-of-range" | "length" | "popped" | "txn" => 4,
+        "status" => 9,
+        _ => 2,
+    }
+}
 
-    //Establish connection
-    let address = TcpListener::bind("127.0.0.1:64500").await.unwrap();
-    loop {
-        let (connection, _) = address.accept().await.unwrap();
-        let (mut connection_reader, _) = io::split(connection);
-        let mut buffer = vec![1; 128];
-
-        loop {
-            let n = connection_reader.read(&mut buffer).await.unwrap();
-            //Error handling - wrong message size
-            if n == 0 || n == 127 {break;}
-            //Get return message from "get" operation; presumably a key-value pair
-            let return_message: String = bincode::deserialize(&buffer[..n]).unwrap();
-            //Split up the message so that its different parts can be examined
-            let message_vector:Vec<&str> = return_message.split(" ").collect();
+//The send_request function sends a request to the node with the given pid, opening a connection to it first if there is none
+//If the node cannot be reached, the request is sent to the next member instead, waiting a little longer before every retry
+//Returns the pid of the node the request was sent to; the responses arrive on its connection and are printed by give_results
+async fn send_request(connections: &mut HashMap<u64, WriteHalf<TcpStream>>, pid: u64, request: String, sender: &mpsc::Sender<(&'static str, Vec<u8>)>, cluster: &Option<ClusterConfig>, failover: &Failover) -> Option<u64> {
+    let mut backoff = failover.backoff;
+    let mut target = pid;
+    for attempt in 0..=failover.retries {
+        if attempt > 0 {
+            sleep(backoff).await;
+            backoff *= 2;
+            //Without other known members the same node is tried again
+            target = next_member(&failover.members, target);
+            println!(" -> Retrying with node {}", target);
+        }
+        match send_to_node(connections, target, &request, sender, cluster, failover).await {
+            Ok(()) => return Some(target),
+            Err(error) => println!(" -> ERROR: Could not reach node {} - {}", target, error),
+        }
+    }
+    println!(" -> ERROR: Giving up after {} attempts", failover.retries + 1);
+    None
+}
 
-            //Error handling - if the "get" was for a key that has not been added
-            if message_vector[0] == "not" {println!(" -> ERROR: Key not found in database - try searching for a key that exists");}
-            //If the key does exist
-            else {println!(" -> Found key-value pair: key {} value {}", message_vector[0], message_vector[1]);}   
+//The next_member function gives the member after the given pid, going round to the first after the last
+fn next_member(members: &[u64], pid: u64) -> u64 {
+    members.iter().copied().find(|member| *member > pid).or(members.first().copied()).unwrap_or(pid)
+}
+
+//The send_to_node function makes a single attempt at sending a request to the node with the given pid
+async fn send_to_node(connections: &mut HashMap<u64, WriteHalf<TcpStream>>, pid: u64, request: &str, sender: &mpsc::Sender<(&'static str, Vec<u8>)>, cluster: &Option<ClusterConfig>, failover: &Failover) -> Result<(), String> {
+    if let Entry::Vacant(vacant_entry) = connections.entry(pid) {
+        //Connect to the right node
+        let stream = match timeout(failover.timeout, TcpStream::connect(node_address(cluster, pid)?)).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(error)) => return Err(error.to_string()),
+            Err(_) => return Err("timed out connecting".to_string()),
+        };
+        let (reader, writer) = io::split(stream);
+        let sender_x = sender.clone();
+        let max_frame_size = failover.max_frame_size;
+        tokio::spawn(async move {
+            give_results(reader, sender_x, max_frame_size).await;
+        });
+        vacant_entry.insert(writer);
+    }
+    //Send the message
+    let writer = connections.get_mut(&pid).unwrap();
+    let encrypted_message: Vec<u8> = bincode::serialize(&request).unwrap();
+    if let Err(error) = write_frame(writer, &encrypted_message).await {
+        //The connection is reopened on the next attempt
+        connections.remove(&pid);
+        return Err(format!("lost connection - {}", error));
+    }
+    Ok(())
+}
+
+//The node_address function gives the client address of the node with the given pid
+//A node that is not in the configuration file, e.g. one added after the file was written, is assumed to be at the default address for its pid
+fn node_address(cluster: &Option<ClusterConfig>, pid: u64) -> Result<String, String> {
+    match cluster.as_ref().and_then(|cluster| cluster.node(pid)) {
+        Some(address) => Ok(address.client_address()),
+        None => NodeAddress::local(pid).map(|address| address.client_address()),
+    }
+}
+
+//The request_arguments function writes the words of a command the way the node expects them, with every value in hex
+//A value can be typed in as text, or as hex:[digits] or base64:[characters] for bytes that are not text
+fn request_arguments(command: &str, message_vector: &[String]) -> Result<String, String> {
+    let mut arguments = vec![];
+    let transaction_values = if command == "txn" { transaction_values(message_vector) } else { vec![] };
+    for (position, word) in message_vector.iter().enumerate() {
+        let is_value = match command {
+            "put" | "put-if-absent" | "lpush" | "rpush" | "sadd" | "srem" => position == 2,
+            "cas" => position == 2 || position == 3,
+            "hset" => position == 3,
+            "txn" => transaction_values.contains(&position),
+            _ => false,
+        };
+        if !is_value {
+            arguments.push(quote(word));
+            continue;
         }
+        let value = if let Some(hex) = word.strip_prefix("hex:") {
+            from_hex(hex)?
+        } else if let Some(base64) = word.strip_prefix("base64:") {
+            from_base64(base64)?
+        } else {
+            word.as_bytes().to_vec()
+        };
+        arguments.push(quote(&to_hex(&value)));
+    }
+    Ok(arguments.join(" "))
+}
+
+//The transaction_values function gives the positions of the values in a txn command - after the key of a put and after the == or != of a guard
+//The node reports any other mistake in the transaction
+fn transaction_values(message_vector: &[String]) -> Vec<usize> {
+    let mut values = vec![];
+    let mut position = 1;
+    while position < message_vector.len() {
+        position += match message_vector[position].as_str() {
+            "if" => match message_vector.get(position + 2).map(|comparison| comparison.as_str()) {
+                Some("==" | "!=") => {
+                    values.push(position + 3);
+                    4
+                },
+                _ => 3,
+            },
+            "put" => {
+                values.push(position + 2);
+                3
+            },
+            "delete" => 2,
+            _ => 1,
+        };
+    }
+    values
+}
+
+//The transaction_key function gives the key a txn is sent by - the key of its first write, or of its first guard if it only checks keys
+//The node files the transaction's entry under the same key
+fn transaction_key(message_vector: &[String]) -> &str {
+    let mut guard_key = None;
+    let mut position = 1;
+    while position < message_vector.len() {
+        let key = message_vector.get(position + 1).map(|key| key.as_str());
+        position += match message_vector[position].as_str() {
+            "if" => {
+                guard_key = guard_key.or(key);
+                match message_vector.get(position + 2).map(|comparison| comparison.as_str()) {
+                    Some("==" | "!=") => 4,
+                    _ => 3,
+                }
+            },
+            "put" | "delete" => return key.unwrap_or_default(),
+            _ => 1,
+        };
+    }
+    guard_key.unwrap_or_default()
+}
+
+//The display_value function shows a value from a response - as text if it is printable UTF-8, otherwise in hex
+fn display_value(hex: &str) -> String {
+    let value = match from_hex(hex) {
+        Ok(value) => value,
+        Err(_) => return format!("(unreadable value {})", hex),
+    };
+    match String::from_utf8(value) {
+        Ok(text) if !text.chars().any(|character| character.is_control()) => quote(&text),
+        _ => format!("hex:{}", hex),
+    }
+}
+
+//The key_node function gives the pid of the node a key is sent to - the 64 bit FNV-1a hash of the key spread over the members, in order of their pids
+//Until the client knows the members, keys go to the node with pid 1
+fn key_node(key: &str, members: &[u64]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in key.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    match members.len() {
+        0 => 1,
+        number_of_members => members[(hash % number_of_members as u64) as usize],
+    }
+}
+
+//The parse_leader function reads the pid and ballot number of a leader from a node's response, which is "none" if the node knows no leader
+fn parse_leader(words: &[String]) -> Option<(u64, u32)> {
+    match words {
+        [pid, n, ..] => Some((pid.parse().ok()?, n.parse().ok()?)),
+        _ => None,
+    }
+}
+
+//The newer_leader function picks between the leader the client knows of and one a node reported, keeping the one elected with the higher ballot
+fn newer_leader(known: Option<(u64, u32)>, reported: (u64, u32)) -> Option<(u64, u32)> {
+    match known {
+        Some((_, n)) if n >= reported.1 => known,
+        _ => Some(reported),
     }
 }
 
 //The message_receiver function handles the messages sent within the client's code
-async fn message_receiver(mut receiver: mpsc::Receiver<(&str, Vec<u8>)>) {
-    //Record of the number of peers (i.e. active nodes - 1), default is 0
-    let mut number_of_peers: u64 = 0; 
+async fn message_receiver(mut receiver: mpsc::Receiver<(&str, Vec<u8>)>, sender: mpsc::Sender<(&'static str, Vec<u8>)>, cluster: Option<ClusterConfig>, mut failover: Failover) {
+    //The pids of the members of the cluster, in ascending order - known from the start when there is a configuration file, and otherwise asked for
+    let mut members: Vec<u64> = match &cluster {
+        Some(cluster) => cluster.pids(),
+        None => vec![],
+    };
+    //Id of the latest request, so that the responses from the nodes can be matched with the requests
+    let mut last_request_id: u64 = 0;
+    //Open connections to the nodes, by pid
+    let mut connections: HashMap<u64, WriteHalf<TcpStream>> = HashMap::new();
+    //The leader as far as the client knows, and the writes sent to it that it may redirect, with how often they have been redirected already
+    //The leader is kept with the ballot number it was elected with, so that an older answer arriving late does not replace a newer one
+    let mut leader: Option<(u64, u32)> = None;
+    let mut redirectable: HashMap<u64, (String, String, u32)> = HashMap::new();
     //Go through messages
     while let Some(action) = receiver.recv().await {
-        match (action.0, action.1) {
-            //Message from a node with an updated number of peers; update the number set here
-            ("peers", updated_number_of_peers) => {
-                let deserialized_update: u64 = bincode::deserialize(&updated_number_of_peers).unwrap();
-                number_of_peers = deserialized_update;
-            },
-            //Put or get message
-            (_, message) => {
-                let mut node = 0;
-                let mut deserialized_message: String = bincode::deserialize(&message).unwrap();
-                let message_vector:Vec<&str> = deserialized_message.split(" ").collect();
-
-                let mut key: u64 = message_vector[1].trim().parse().expect(" -> ERROR: The key needs to be a number");
-                if action.0 == "put" && message_vector.len() == 2 {
-                    println!(" -> ERROR: Put message requires a value");
-                }
-                else if action.0 == "get" || message_vector.len() == 3 {
-                    //Loop to see which node gets the message; each node handles up to five keys, other than the last one which handles everything higher
-                    //than the key of the second last node
-                    for number in 0..key {
-                        if number % 5 == 0{
-                            node = node + 1;
-                        }
-                        if number == key || node >= number_of_peers{
-                            break;
-                        }
-                    } 
-                    //Connect to the right node
-                    let mut address: String = "127.0.0.1:".to_owned();
-                    let port_of_node: u64 = 64500 + node;
-                    address.push_str(&port_of_node.to_string().to_owned()); 
-                    let stream = TcpStream::connect(address).await.unwrap();
-                    //Print to the client so that it is possible to see what is going on
-                    if action.0 == "put"{
-                        println!(" -> Sending put message to node {}", node);
-                    }
-                    else {
-                        println!(" -> Sending get message to node {}", node);
-                    }
-                    //Send the message
-                    let (_reader, mut writer) = tokio::io::split(stream);    
-                    let encrypted_message: Vec<u8> = bincode::serialize(&deserialized_message.trim()).unwrap();
-                    writer.write_all(&encrypted_message).await.unwrap();
+        let (command, deserialized_message, redirects): (String, String, u32) = match (action.0, action.1) {
+            //Message from a node with the pids of the members; none means they have changed and have to be asked for again
+            ("nodes", updated_members) => {
+                members = bincode::deserialize(&updated_members).unwrap();
+                members.sort_unstable();
+                continue;
+            },
+            //A leader a node reported, which only replaces the one the client knows of if its ballot is newer
+            //The cluster moving on to a new configuration forgets the leader, as ballots start over with it
+            ("leader", updated_leader) => {
+                leader = match bincode::deserialize::<Option<(u64, u32)>>(&updated_leader).unwrap() {
+                    Some(reported) => newer_leader(leader, reported),
+                    None => None,
+                };
+                continue;
+            },
+            //The write is sent again, to the leader the node named if it knew one
+            ("redirect", redirect) => {
+                let (request_id, new_leader): (u64, Option<(u64, u32)>) = bincode::deserialize(&redirect).unwrap();
+                if let Some(reported) = new_leader {
+                    leader = newer_leader(leader, reported);
                 }
-                else{
-                    println!(" -> ERROR: Unforeseen error when trying to put or get");
+                match redirectable.remove(&request_id) {
+                    Some((command, message, redirects)) => {
+                        println!(" -> Write {} was sent to a node that is not the leader - sending it again", request_id);
+                        (command, message, redirects + 1)
+                    },
+                    None => continue,
                 }
             },
-            //If a message type not implemented here is received
-            _ => {
-                println!(" -> ERROR: Received an unknown message type");
+            //Put, get, delete, conditional write or membership change message
+            (command, message) => (command.to_string(), bincode::deserialize(&message).unwrap(), 0),
+        };
+        let command = command.as_str();
+        //Keys can be any string - one with spaces is written in quotes
+        let message_vector: Vec<String> = match split_words(&deserialized_message) {
+            Ok(message_vector) => message_vector,
+            Err(error) => {
+                println!(" -> ERROR: {}", error);
+                continue;
             },
+        };
+        if message_vector.len() < required_words(command) {
+            println!(" -> ERROR: {} message requires {} arguments", command, required_words(command) - 1);
+        }
+        else {
+            //The node a key belongs to is picked by its hash, so that keys spread evenly over the nodes whatever they look like
+            //A txn names several keys, and goes by the one the node files it under
+            let key = if command == "txn" { transaction_key(&message_vector) } else { message_vector[1].as_str() };
+            let mut node = key_node(key, &members);
+            //The status is asked of a specific node, given by its pid
+            if command == "status" {
+                node = match message_vector[1].parse::<u64>() {
+                    Ok(pid) if pid > 0 => pid,
+                    _ => {
+                        println!(" -> ERROR: The pid needs to be a number of at least 1");
+                        continue;
+                    },
+                };
+            }
+            //Any node can fail over to the others, except for the status, which is asked of a specific node
+            failover.members = if command == "status" { vec![] } else { members.clone() };
+            //Until a node has told us the members, ask the node we are sending to
+            if members.is_empty() {
+                last_request_id += 1;
+                send_request(&mut connections, node, format!("{} nodes", last_request_id), &sender, &cluster, &failover).await;
+            }
+            //A write goes to the leader if the client knows it, marked so that the node redirects it if it is no longer the leader
+            //Otherwise the node the key maps to forwards it, and is asked who the leader is for the next write
+            let mut prefix = "";
+            if WRITES.contains(&command) {
+                match leader {
+                    Some((leader, _)) if redirects < MAX_REDIRECTS => {
+                        node = leader;
+                        prefix = "to-leader ";
+                    },
+                    _ => {
+                        last_request_id += 1;
+                        send_request(&mut connections, node, format!("{} leader", last_request_id), &sender, &cluster, &failover).await;
+                    },
+                }
+            }
+            //Every request gets a new request id, which the node refers to in its response
+            last_request_id += 1;
+            //Values are sent in hex, whichever way they were typed in
+            let arguments = match request_arguments(command, &message_vector) {
+                Ok(arguments) => arguments,
+                Err(error) => {
+                    println!(" -> ERROR: {}", error);
+                    continue;
+                },
+            };
+            let request = format!("{} {}{}", last_request_id, prefix, arguments);
+            if !prefix.is_empty() {
+                redirectable.retain(|request_id, _| request_id + REDIRECT_WINDOW > last_request_id);
+                redirectable.insert(last_request_id, (command.to_string(), deserialized_message.clone(), redirects));
+            }
+            //Print to the client so that it is possible to see what is going on, including which node ended up with the request
+            if let Some(served_by) = send_request(&mut connections, node, request, &sender, &cluster, &failover).await {
+                println!(" -> Sent {} message {} to node {}", command, last_request_id, served_by);
+            }
         }
     }
-}
\ No newline at end of file
+}
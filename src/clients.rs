@@ -0,0 +1,39 @@
+//Imports
+//Tokio - used for handing responses to the connection tasks
+use tokio::sync::mpsc::{self, error::TrySendError};
+//Used for the shared registry of connections
+use std::{collections::HashMap, sync::{Arc, Mutex}};
+
+//The Clients struct is the registry of connected clients, by connection id
+//Each connection task registers a queue here; responses put in the queue are written back on the connection the request arrived on
+#[derive(Clone, Default)]
+pub struct Clients {
+    connections: Arc<Mutex<HashMap<u64, mpsc::Sender<String>>>>,
+}
+
+impl Clients {
+    //Register the response queue of a newly accepted connection
+    pub fn register(&self, connection: u64, responses: mpsc::Sender<String>) {
+        self.connections.lock().unwrap().insert(connection, responses);
+    }
+
+    //Forget a connection once the client has disconnected
+    pub fn remove(&self, connection: u64) {
+        self.connections.lock().unwrap().remove(&connection);
+    }
+
+    //Send the response to a request back to the client; the request id lets the client match it with its request
+    pub fn reply(&self, connection: u64, request_id: u64, response: &str) {
+        let connections = self.connections.lock().unwrap();
+        match connections.get(&connection) {
+            Some(responses) => match responses.try_send(format!("{} {}", request_id, response)) {
+                Ok(()) => {},
+                //A client that does not read its responses must not hold up the node
+                Err(TrySendError::Full(_)) => println!("ERROR: Response queue of client connection {} is full - dropping response", connection),
+                Err(TrySendError::Closed(_)) => println!("ERROR: Client connection {} has closed", connection),
+            },
+            //The client disconnected before the response was ready
+            None => println!("Client connection {} is gone - dropping response to request {}", connection, request_id),
+        }
+    }
+}
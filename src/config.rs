@@ -0,0 +1,183 @@
+//Imports
+//Serde - used for reading the configuration file
+use serde::{Serialize, Deserialize};
+//Used for reading the file
+use std::{fs, path::Path};
+
+//Ports used when no configuration file is given: each node adds its pid to these
+pub const DEFAULT_SP_PORT_BASE: u64 = 50000;
+pub const DEFAULT_BLE_PORT_BASE: u64 = 60000;
+pub const DEFAULT_CLIENT_PORT_BASE: u64 = 64500;
+//Host used when no configuration file is given
+pub const DEFAULT_HOST: &str = "127.0.0.1";
+
+//The NodeAddress struct holds where one node of the cluster can be reached
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeAddress {
+    pub pid: u64,
+    pub host: String,
+    //Port for SequencePaxos traffic between the nodes
+    pub sp_port: u16,
+    //Port for BallotLeaderElection traffic between the nodes
+    pub ble_port: u16,
+    //Port that clients send their requests to
+    pub client_port: u16,
+}
+
+impl NodeAddress {
+    //The address a node has when no configuration file is given
+    //Only pids small enough for every default port to stay below 65536 have one
+    pub fn local(pid: u64) -> Result<NodeAddress, String> {
+        let port = |base: u64| base.checked_add(pid).and_then(|port| u16::try_from(port).ok()).ok_or(format!("pid {} is too large for the default ports", pid));
+        Ok(NodeAddress {
+            pid,
+            host: DEFAULT_HOST.to_string(),
+            sp_port: port(DEFAULT_SP_PORT_BASE)?,
+            ble_port: port(DEFAULT_BLE_PORT_BASE)?,
+            client_port: port(DEFAULT_CLIENT_PORT_BASE)?,
+        })
+    }
+
+    //Read an address written "host" (the default ports for the pid) or "host:sp_port:ble_port:client_port"
+    pub fn parse(pid: u64, address: &str) -> Result<NodeAddress, String> {
+        let parts: Vec<&str> = address.split(':').collect();
+        match parts.len() {
+            1 => Ok(NodeAddress { host: parts[0].to_string(), ..NodeAddress::local(pid)? }),
+            4 => {
+                let port = |part: &str| part.parse::<u16>().map_err(|_| format!("{} is not a port", part));
+                Ok(NodeAddress { pid, host: parts[0].to_string(), sp_port: port(parts[1])?, ble_port: port(parts[2])?, client_port: port(parts[3])? })
+            },
+            _ => Err(format!("{} should be written host or host:sp_port:ble_port:client_port", address)),
+        }
+    }
+
+    pub fn sp_address(&self) -> String {
+        format!("{}:{}", self.host, self.sp_port)
+    }
+
+    pub fn ble_address(&self) -> String {
+        format!("{}:{}", self.host, self.ble_port)
+    }
+
+    pub fn client_address(&self) -> String {
+        format!("{}:{}", self.host, self.client_port)
+    }
+}
+
+//The ClusterConfig struct lists every node of the cluster; it is read from a TOML file with one [[nodes]] table per node:
+//
+//  [[nodes]]
+//  pid = 1
+//  host = "127.0.0.1"
+//  sp_port = 50001
+//  ble_port = 60001
+//  client_port = 64501
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ClusterConfig {
+    pub nodes: Vec<NodeAddress>,
+}
+
+impl ClusterConfig {
+    //Read the configuration file
+    pub fn load(path: &Path) -> Result<ClusterConfig, String> {
+        let contents = fs::read_to_string(path).map_err(|error| format!("could not read {:?} - {}", path, error))?;
+        let config: ClusterConfig = toml::from_str(&contents).map_err(|error| format!("could not parse {:?} - {}", path, error))?;
+        //Every pid may only appear once, otherwise it is unclear which address to use
+        let mut pids = config.pids();
+        pids.dedup();
+        if pids.len() != config.nodes.len() {
+            return Err(format!("{:?} lists the same pid more than once", path));
+        }
+        Ok(config)
+    }
+
+    //The configuration used when no file is given: every node on the local host at the default ports
+    pub fn local(pids: &[u64]) -> Result<ClusterConfig, String> {
+        Ok(ClusterConfig {
+            nodes: pids.iter().map(|pid| NodeAddress::local(*pid)).collect::<Result<_, _>>()?,
+        })
+    }
+
+    //Look up a node by pid
+    pub fn node(&self, pid: u64) -> Option<&NodeAddress> {
+        self.nodes.iter().find(|node| node.pid == pid)
+    }
+
+    //The pids of all nodes, in ascending order
+    pub fn pids(&self) -> Vec<u64> {
+        let mut pids: Vec<u64> = self.nodes.iter().map(|node| node.pid).collect();
+        pids.sort_unstable();
+        pids
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const TWO_NODES: &str = "
+[[nodes]]
+pid = 1
+host = \"10.0.0.1\"
+sp_port = 50001
+ble_port = 60001
+client_port = 64501
+
+[[nodes]]
+pid = 2
+host = \"10.0.0.2\"
+sp_port = 50002
+ble_port = 60002
+client_port = 64502
+";
+
+    //Write a configuration file for a test - the pid keeps test runs that happen at the same time apart
+    fn config_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("omnipaxos-kv-config-{}-{}.toml", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn loads_the_nodes() {
+        let config = ClusterConfig::load(&config_file("valid", TWO_NODES)).unwrap();
+        assert_eq!(config.pids(), vec![1, 2]);
+        assert_eq!(config.node(2).unwrap().client_address(), "10.0.0.2:64502");
+        assert_eq!(config.node(1).unwrap().sp_address(), "10.0.0.1:50001");
+        //A node that is not in the file has no address
+        assert!(config.node(3).is_none());
+    }
+
+    #[test]
+    fn rejects_duplicate_pids() {
+        let duplicated = TWO_NODES.replace("pid = 2", "pid = 1");
+        let error = ClusterConfig::load(&config_file("duplicate", &duplicated)).unwrap_err();
+        assert!(error.contains("more than once"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        //No file at all
+        assert!(ClusterConfig::load(&std::env::temp_dir().join("omnipaxos-kv-config-missing.toml")).is_err());
+        //Not TOML
+        assert!(ClusterConfig::load(&config_file("not-toml", "[[nodes]\npid = ")).is_err());
+        //A node without one of its ports
+        assert!(ClusterConfig::load(&config_file("missing-port", &TWO_NODES.replace("ble_port = 60002\n", ""))).is_err());
+        //A port out of range
+        assert!(ClusterConfig::load(&config_file("bad-port", &TWO_NODES.replace("50002", "70000"))).is_err());
+    }
+
+    #[test]
+    fn default_addresses_need_a_small_pid() {
+        let address = NodeAddress::local(3).unwrap();
+        assert_eq!((address.sp_address(), address.ble_address(), address.client_address()), ("127.0.0.1:50003".to_string(), "127.0.0.1:60003".to_string(), "127.0.0.1:64503".to_string()));
+        assert!(NodeAddress::local(1035).is_ok());
+        assert!(NodeAddress::local(1036).is_err());
+        assert!(NodeAddress::local(u64::MAX).is_err());
+        assert!(ClusterConfig::local(&[1, 2, 100000]).is_err());
+        assert_eq!(NodeAddress::parse(4, "10.0.0.4").unwrap().client_address(), "10.0.0.4:64504");
+        assert!(NodeAddress::parse(5000, "10.0.0.4").is_err());
+        assert_eq!(NodeAddress::parse(5000, "10.0.0.4:1:2:3").unwrap().client_address(), "10.0.0.4:3");
+    }
+}
@@ -0,0 +1,234 @@
+//Imports
+//Length-prefixed framing shared with the client
+use omnipaxos_key_value_store::framing::write_frame;
+//Tokio - used for network stuff
+use tokio::{
+    net::TcpStream,
+    sync::mpsc::{self, error::TrySendError},
+    time::sleep,
+};
+//Used for the peer addresses and the backoff timer
+use std::{collections::HashMap, time::Duration};
+
+//Number of messages that can be queued for a peer while it is being (re)connected to - further messages are dropped
+const QUEUE_SIZE: usize = 1024;
+//Delay before the first reconnection attempt; it doubles for every failed attempt up to MAX_BACKOFF
+const INITIAL_BACKOFF: Duration = Duration::from_millis(50);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+//The PeerState enum describes the connection to one peer; every change is reported in the node's output
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerState {
+    Connecting,
+    Connected,
+    Backoff { attempt: u32 },
+}
+
+//The PeerConnections struct keeps one long-lived connection per peer for a single kind of traffic (SP or BLE)
+//Each peer gets its own task that owns the stream; messages reach it through a bounded queue
+pub struct PeerConnections {
+    name: &'static str,
+    addresses: HashMap<u64, String>,
+    queues: HashMap<u64, mpsc::Sender<Vec<u8>>>,
+    //Largest message the peers accept - a larger one would make the peer drop the connection, and it would be resent forever
+    max_frame_size: usize,
+}
+
+impl PeerConnections {
+    //Create the manager; connections are opened lazily when the first message for a peer is sent
+    pub fn new(name: &'static str, addresses: HashMap<u64, String>, max_frame_size: usize) -> PeerConnections {
+        PeerConnections {
+            name,
+            addresses,
+            queues: HashMap::new(),
+            max_frame_size,
+        }
+    }
+
+    //The pids of every peer this manager can send to
+    pub fn peers(&self) -> Vec<u64> {
+        self.addresses.keys().copied().collect()
+    }
+
+    //Switch to the peers of a new configuration; connections to peers that are no longer in it are closed
+    pub fn set_addresses(&mut self, addresses: HashMap<u64, String>) {
+        //Dropping the queue of a peer makes its connection task stop
+        self.queues.retain(|pid, _| addresses.contains_key(pid));
+        self.addresses = addresses;
+    }
+
+    //Add peers without closing the connections to the current ones
+    pub fn add_addresses(&mut self, addresses: HashMap<u64, String>) {
+        self.addresses.extend(addresses);
+    }
+
+    //Queue an already serialized message for a peer
+    pub fn send(&mut self, to: u64, message: Vec<u8>) {
+        let name = self.name;
+        if message.len() > self.max_frame_size {
+            println!("ERROR: Not sending a {} message of {} bytes to peer {} - it is above the maximum frame size of {} bytes", name, message.len(), to, self.max_frame_size);
+            return;
+        }
+        let queue = match self.queues.get(&to) {
+            Some(queue) => queue,
+            None => {
+                let address = match self.addresses.get(&to) {
+                    Some(address) => address.clone(),
+                    None => {
+                        println!("ERROR: No {} address known for peer {}", name, to);
+                        return;
+                    },
+                };
+                let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+                tokio::spawn(async move {
+                    peer_connection(name, to, address, receiver).await;
+                });
+                self.queues.entry(to).or_insert(sender)
+            },
+        };
+        match queue.try_send(message) {
+            Ok(()) => {},
+            //The peer is unreachable for long enough that the queue filled up; Paxos copes with lost messages
+            Err(TrySendError::Full(_)) => println!("ERROR: {} queue for peer {} is full - dropping message", name, to),
+            Err(TrySendError::Closed(_)) => println!("ERROR: {} connection task for peer {} has stopped", name, to),
+        }
+    }
+}
+
+//The peer_connection function owns the connection to one peer, reconnecting with exponential backoff whenever it breaks
+//A message whose write failed is sent again first after reconnecting. The failed write may still have reached the peer,
+//so the peer can receive it twice - the node has to cope with that, as it copes with messages dropped from a full queue
+async fn peer_connection(name: &'static str, pid: u64, address: String, mut receiver: mpsc::Receiver<Vec<u8>>) {
+    let mut unsent: Option<Vec<u8>> = None;
+    loop {
+        let mut stream = connect_with_backoff(name, pid, &address).await;
+        loop {
+            let message = match unsent.take() {
+                Some(message) => message,
+                None => match receiver.recv().await {
+                    Some(message) => message,
+                    //The node is shutting down
+                    None => return,
+                },
+            };
+            if let Err(error) = write_frame(&mut stream, &message).await {
+                println!("ERROR: Lost {} connection to peer {} - {}", name, pid, error);
+                unsent = Some(message);
+                break;
+            }
+        }
+    }
+}
+
+//The connect_with_backoff function keeps trying to connect to a peer until it succeeds
+async fn connect_with_backoff(name: &'static str, pid: u64, address: &str) -> TcpStream {
+    report_state(name, pid, PeerState::Connecting);
+    let mut attempt = 0;
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        match TcpStream::connect(address).await {
+            Ok(stream) => {
+                //Messages are small and latency-sensitive, so do not wait to fill packets
+                let _ = stream.set_nodelay(true);
+                report_state(name, pid, PeerState::Connected);
+                return stream;
+            },
+            Err(_) => {
+                attempt += 1;
+                report_state(name, pid, PeerState::Backoff { attempt });
+                sleep(backoff).await;
+                backoff = next_backoff(backoff);
+            },
+        }
+    }
+}
+
+//The next_backoff function gives the delay before the next reconnection attempt
+fn next_backoff(backoff: Duration) -> Duration {
+    (backoff * 2).min(MAX_BACKOFF)
+}
+
+//The report_state function prints a peer's new connection state
+fn report_state(name: &str, pid: u64, state: PeerState) {
+    if let Some(report) = state_report(name, pid, state) {
+        println!("{}", report);
+    }
+}
+
+//The state_report function gives the line to print for a peer's new connection state, if it is worth printing
+fn state_report(name: &str, pid: u64, state: PeerState) -> Option<String> {
+    match state {
+        //Only report the first failed attempt and then every tenth, so that a peer that is down does not flood the output
+        PeerState::Backoff { attempt } if attempt != 1 && attempt % 10 != 0 => None,
+        _ => Some(format!("{} connection to peer {}: {:?}", name, pid, state)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use omnipaxos_key_value_store::framing::{read_frame, DEFAULT_MAX_FRAME_SIZE};
+    use tokio::{net::TcpListener, time::timeout};
+
+    //An address on this machine that nothing listens on until the test binds it again
+    async fn free_address() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    #[test]
+    fn doubles_the_backoff_up_to_the_maximum() {
+        let mut backoff = INITIAL_BACKOFF;
+        let mut delays = vec![];
+        for _ in 0..10 {
+            delays.push(backoff.as_millis());
+            backoff = next_backoff(backoff);
+        }
+        assert_eq!(delays, vec![50, 100, 200, 400, 800, 1600, 3200, 5000, 5000, 5000]);
+    }
+
+    #[test]
+    fn reports_the_first_and_every_tenth_failed_attempt() {
+        assert_eq!(state_report("SP", 2, PeerState::Connecting), Some("SP connection to peer 2: Connecting".to_string()));
+        assert_eq!(state_report("SP", 2, PeerState::Connected), Some("SP connection to peer 2: Connected".to_string()));
+        let reported: Vec<u32> = (1..=30).filter(|attempt| state_report("BLE", 3, PeerState::Backoff { attempt: *attempt }).is_some()).collect();
+        assert_eq!(reported, vec![1, 10, 20, 30]);
+        assert_eq!(state_report("BLE", 3, PeerState::Backoff { attempt: 10 }), Some("BLE connection to peer 3: Backoff { attempt: 10 }".to_string()));
+    }
+
+    #[tokio::test]
+    async fn reconnects_once_the_peer_is_up() {
+        let address = free_address().await;
+        let mut connections = PeerConnections::new("SP", HashMap::from([(2, address.clone())]), DEFAULT_MAX_FRAME_SIZE);
+        connections.send(2, b"first".to_vec());
+        //The first attempts fail and the connection task backs off
+        sleep(Duration::from_millis(120)).await;
+        let listener = TcpListener::bind(&address).await.unwrap();
+        let (mut stream, _) = timeout(Duration::from_secs(5), listener.accept()).await.unwrap().unwrap();
+        assert_eq!(read_frame(&mut stream, DEFAULT_MAX_FRAME_SIZE).await.unwrap(), Some(b"first".to_vec()));
+        connections.send(2, b"second".to_vec());
+        assert_eq!(read_frame(&mut stream, DEFAULT_MAX_FRAME_SIZE).await.unwrap(), Some(b"second".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn drops_messages_once_the_queue_is_full() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let mut connections = PeerConnections::new("SP", HashMap::from([(2, address)]), DEFAULT_MAX_FRAME_SIZE);
+        //The connection task does not get to run before the test waits, so nothing leaves the queue in the meantime
+        for message in 0..QUEUE_SIZE as u32 + 10 {
+            connections.send(2, message.to_be_bytes().to_vec());
+        }
+        assert_eq!(connections.queues[&2].capacity(), 0);
+        //The messages that fit are delivered in order, the ones after them are gone
+        let (mut stream, _) = timeout(Duration::from_secs(5), listener.accept()).await.unwrap().unwrap();
+        for message in 0..QUEUE_SIZE as u32 {
+            assert_eq!(read_frame(&mut stream, DEFAULT_MAX_FRAME_SIZE).await.unwrap(), Some(message.to_be_bytes().to_vec()));
+        }
+        assert!(timeout(Duration::from_millis(100), read_frame(&mut stream, DEFAULT_MAX_FRAME_SIZE)).await.is_err());
+        //Messages above the maximum frame size are not queued at all
+        let mut small = PeerConnections::new("SP", HashMap::from([(2, free_address().await)]), 4);
+        small.send(2, vec![0; 5]);
+        assert!(small.queues.is_empty());
+    }
+}
@@ -0,0 +1,55 @@
+//Imports
+//Used for naming the file in errors
+use std::path::Path;
+
+//Every file the node keeps its state in starts with these bytes and the version of the format the rest of the file is written in
+const MAGIC: &[u8; 4] = b"OPKV";
+//The version goes up whenever log entries, snapshots or the metadata are serialized differently, so that a node never misreads what another version wrote
+//Version 1 is the format from before files had a header - values were numbers and entries were puts and deletes only
+//Version 2 has byte values, entries that carry an operation, lists, sets and hashes, transactions and the no-op entry of a new leader
+pub const FORMAT_VERSION: u32 = 2;
+
+//The with_header function puts the header in front of the serialized contents of a file
+pub fn with_header(bytes: &[u8]) -> Vec<u8> {
+    let mut file = Vec::with_capacity(MAGIC.len() + 4 + bytes.len());
+    file.extend_from_slice(MAGIC);
+    file.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    file.extend_from_slice(bytes);
+    file
+}
+
+//The strip_header function checks the header of a file and returns the contents after it
+//A file in another format is refused, as there is no way to tell what its contents would be misread as
+pub fn strip_header<'a>(bytes: &'a [u8], path: &Path) -> Result<&'a [u8], String> {
+    let version = match bytes.strip_prefix(MAGIC) {
+        Some(rest) if rest.len() >= 4 => u32::from_le_bytes(rest[..4].try_into().unwrap()),
+        _ => 1,
+    };
+    if version != FORMAT_VERSION {
+        return Err(format!("{:?} is in data format version {}, but this node reads version {} - start it with an empty data directory, and it gets the key-value pairs from the other nodes", path, version, FORMAT_VERSION));
+    }
+    Ok(&bytes[MAGIC.len() + 4..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let file = with_header(b"contents");
+        assert_eq!(strip_header(&file, Path::new("metadata")), Ok(&b"contents"[..]));
+        assert_eq!(strip_header(&with_header(b""), Path::new("metadata")), Ok(&b""[..]));
+    }
+
+    #[test]
+    fn refuses_other_versions() {
+        //A file from before the header existed
+        assert!(strip_header(b"\x01\x00\x00\x00\x00\x00\x00\x00", Path::new("snapshot")).is_err());
+        assert!(strip_header(b"", Path::new("snapshot")).is_err());
+        assert!(strip_header(b"OPKV", Path::new("snapshot")).is_err());
+        let mut newer = MAGIC.to_vec();
+        newer.extend_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert!(strip_header(&newer, Path::new("snapshot")).is_err());
+    }
+}
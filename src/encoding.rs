@@ -0,0 +1,113 @@
+//Values are arbitrary bytes, but requests and responses are text
+//On the wire every value is written in hex; the client also accepts base64 and plain text from the user
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+//The to_hex function writes bytes as lowercase hex, two digits per byte
+pub fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        hex.push(HEX_DIGITS[(byte >> 4) as usize] as char);
+        hex.push(HEX_DIGITS[(byte & 0xf) as usize] as char);
+    }
+    hex
+}
+
+//The from_hex function reads bytes written in hex, in either case
+pub fn from_hex(hex: &str) -> Result<Vec<u8>, String> {
+    if hex.len() % 2 == 1 {
+        return Err("hex values need two digits per byte".to_string());
+    }
+    let digit = |character: u8| -> Result<u8, String> {
+        (character as char).to_digit(16).map(|digit| digit as u8).ok_or_else(|| format!("{} is not a hex digit", character as char))
+    };
+    hex.as_bytes().chunks(2).map(|pair| Ok(digit(pair[0])? << 4 | digit(pair[1])?)).collect()
+}
+
+//The from_base64 function reads bytes written in standard base64, with or without padding
+//Padding has to make the length a multiple of four, and the bits left over after the last byte have to be zero, so every value has only one spelling
+pub fn from_base64(base64: &str) -> Result<Vec<u8>, String> {
+    let trimmed = base64.trim_end_matches('=');
+    let padding = base64.len() - trimmed.len();
+    if padding > 2 || (padding > 0 && padding != 4 - trimmed.len() % 4) {
+        return Err("the base64 value has the wrong padding".to_string());
+    }
+    //A single leftover character cannot be valid
+    if trimmed.len() % 4 == 1 {
+        return Err("the base64 value has the wrong length".to_string());
+    }
+    let mut bytes = Vec::with_capacity(trimmed.len() * 3 / 4);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for character in trimmed.bytes() {
+        let value = BASE64_ALPHABET.iter().position(|letter| *letter == character).ok_or_else(|| format!("{} is not a base64 character", character as char))?;
+        //Only the bits that have not been turned into bytes yet are kept
+        buffer = (buffer << 6 | value as u32) & 0xffff;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    if buffer & ((1 << bits) - 1) != 0 {
+        return Err("the base64 value has bits left over after its last byte".to_string());
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_round_trip() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        assert_eq!(from_hex(&to_hex(&bytes)), Ok(bytes));
+        assert_eq!(to_hex(b""), "");
+        assert_eq!(from_hex(""), Ok(vec![]));
+        assert_eq!(from_hex("00fF7a"), Ok(vec![0x00, 0xff, 0x7a]));
+    }
+
+    #[test]
+    fn hex_bad_input() {
+        assert!(from_hex("abc").is_err());
+        assert!(from_hex("0g").is_err());
+        assert!(from_hex("+1").is_err());
+        assert!(from_hex("é0").is_err());
+    }
+
+    #[test]
+    fn base64_with_and_without_padding() {
+        assert_eq!(from_base64(""), Ok(vec![]));
+        assert_eq!(from_base64("Zg=="), Ok(b"f".to_vec()));
+        assert_eq!(from_base64("Zg"), Ok(b"f".to_vec()));
+        assert_eq!(from_base64("Zm8="), Ok(b"fo".to_vec()));
+        assert_eq!(from_base64("Zm8"), Ok(b"fo".to_vec()));
+        assert_eq!(from_base64("Zm9v"), Ok(b"foo".to_vec()));
+        assert_eq!(from_base64("eyJhIjogMX0="), Ok(b"{\"a\": 1}".to_vec()));
+        assert_eq!(from_base64("//79"), Ok(vec![0xff, 0xfe, 0xfd]));
+    }
+
+    #[test]
+    fn base64_bad_padding() {
+        assert!(from_base64("Zg===").is_err());
+        assert!(from_base64("Zg=").is_err());
+        assert!(from_base64("Zm9v=").is_err());
+        assert!(from_base64("=").is_err());
+        assert!(from_base64("====").is_err());
+        assert!(from_base64("Zg==Zg==").is_err());
+        assert!(from_base64("Z=g=").is_err());
+    }
+
+    #[test]
+    fn base64_bad_input() {
+        assert!(from_base64("Z").is_err());
+        assert!(from_base64("Zm9vY").is_err());
+        assert!(from_base64("Zm9v!").is_err());
+        assert!(from_base64("Zm 9v").is_err());
+        //Bits left over after the last byte
+        assert!(from_base64("Zh==").is_err());
+        assert!(from_base64("Zm9=").is_err());
+    }
+}
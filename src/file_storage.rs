@@ -0,0 +1,403 @@
+//Imports
+use crate::{KeyValue, data_format::{strip_header, with_header}};
+//OmniPaxos library - the storage trait and the types it persists
+use omnipaxos_core::{
+    ballot_leader_election::Ballot,
+    storage::{StopSign, StopSignEntry, Storage},
+};
+//Serde - used for serializing the metadata file and the log entries
+use serde::{Serialize, Deserialize};
+//Used for the files themselves
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+//Number of log entries per segment file before a new segment is started
+const SEGMENT_ENTRIES: usize = 1024;
+//Name of the metadata file inside the data directory
+const METADATA_FILE: &str = "metadata";
+
+//The Metadata struct holds everything except the log itself; it is small and rewritten in full on every change
+#[derive(Default, Serialize, Deserialize)]
+struct Metadata {
+    promise: Ballot,
+    accepted_round: Ballot,
+    decided_idx: u64,
+    compacted_idx: u64,
+    //Absolute index of the first entry still in the log (i.e. the number of entries removed by trim)
+    log_start: u64,
+    stopsign: Option<(StopSign, bool)>,
+    snapshot: Option<()>,
+}
+
+//A Segment is one append-only file of the log, named after the absolute index of its first entry
+struct Segment {
+    start: u64,
+    path: PathBuf,
+    //Byte offset of every entry written to the file, used when the log has to be truncated
+    offsets: Vec<u64>,
+    size: u64,
+}
+
+//The FileStorage struct implements the OmniPaxos storage trait on top of a data directory:
+//the log is kept in append-only segment files and the ballots and indices in a small metadata file
+//All entries are also kept in memory, so reads never touch the disk
+pub struct FileStorage {
+    dir: PathBuf,
+    metadata: Metadata,
+    log: Vec<KeyValue>,
+    segments: Vec<Segment>,
+    //Whether a previous run left state behind, i.e. the node is restarting after a crash or shutdown
+    recovered: bool,
+}
+
+impl FileStorage {
+    //Open the storage in the given directory, loading whatever state a previous run left behind
+    pub fn open(dir: &Path) -> FileStorage {
+        fs::create_dir_all(dir).expect("ERROR: Could not create the data directory");
+        //Load the metadata - a missing file means that this is a fresh node
+        //The metadata file carries the format version, which covers the log segments as well
+        let path = dir.join(METADATA_FILE);
+        let (metadata, recovered) = match fs::read(&path) {
+            Ok(bytes) => {
+                let bytes = strip_header(&bytes, &path).unwrap_or_else(|error| panic!("ERROR: {}", error));
+                (bincode::deserialize(bytes).expect("ERROR: The metadata file is corrupt"), true)
+            },
+            Err(_) => (Metadata::default(), false),
+        };
+        let mut storage = FileStorage {
+            dir: dir.to_path_buf(),
+            metadata,
+            log: vec![],
+            segments: vec![],
+            recovered,
+        };
+        storage.load_segments();
+        if !recovered {
+            //Log segments without a metadata file cannot have been written in this format
+            if !storage.segments.is_empty() {
+                panic!("ERROR: {:?} holds log segments but no metadata file - start the node with an empty data directory", dir);
+            }
+            storage.persist_metadata();
+        }
+        storage
+    }
+
+    pub fn has_state(&self) -> bool {
+        self.recovered || !self.log.is_empty()
+    }
+
+    //Read all segment files in order and rebuild the in-memory log
+    fn load_segments(&mut self) {
+        let mut starts: Vec<u64> = fs::read_dir(&self.dir)
+            .expect("ERROR: Could not read the data directory")
+            .filter_map(|dir_entry| {
+                let name = dir_entry.ok()?.file_name().into_string().ok()?;
+                name.strip_prefix("segment-")?.strip_suffix(".log")?.parse().ok()
+            })
+            .collect();
+        starts.sort_unstable();
+
+        for start in starts {
+            let path = self.segment_path(start);
+            let mut bytes = vec![];
+            File::open(&path).and_then(|mut file| file.read_to_end(&mut bytes)).expect("ERROR: Could not read log segment");
+            let mut offsets = vec![];
+            let mut position = 0;
+            //Each record is a four byte length followed by the serialized entry
+            while position + 4 <= bytes.len() {
+                let length = u32::from_le_bytes(bytes[position..position + 4].try_into().unwrap()) as usize;
+                if position + 4 + length > bytes.len() {
+                    break;
+                }
+                let entry: KeyValue = bincode::deserialize(&bytes[position + 4..position + 4 + length]).expect("ERROR: Corrupt entry in log segment");
+                //Entries before log_start were trimmed but are still in a partially trimmed segment
+                if start + offsets.len() as u64 >= self.metadata.log_start {
+                    self.log.push(entry);
+                }
+                offsets.push(position as u64);
+                position += 4 + length;
+            }
+            //Anything after the last complete record is a write that was interrupted by a crash
+            if position < bytes.len() {
+                println!("Discarding {} bytes of incomplete log entry in {:?}", bytes.len() - position, path);
+                OpenOptions::new().write(true).open(&path).and_then(|file| file.set_len(position as u64)).expect("ERROR: Could not repair log segment");
+            }
+            self.segments.push(Segment { start, path, offsets, size: position as u64 });
+        }
+    }
+
+    fn segment_path(&self, start: u64) -> PathBuf {
+        self.dir.join(format!("segment-{:020}.log", start))
+    }
+
+    //Write the metadata to a temporary file first and then rename it, so that a crash never leaves a half-written file
+    fn persist_metadata(&self) {
+        let temporary_path = self.dir.join(format!("{}.tmp", METADATA_FILE));
+        let bytes = with_header(&bincode::serialize(&self.metadata).unwrap());
+        let mut file = File::create(&temporary_path).expect("ERROR: Could not write the metadata file");
+        file.write_all(&bytes).and_then(|_| file.sync_all()).expect("ERROR: Could not write the metadata file");
+        fs::rename(&temporary_path, self.dir.join(METADATA_FILE)).expect("ERROR: Could not replace the metadata file");
+    }
+
+    //Append entries to the end of the last segment, starting new segments when the current one is full
+    fn write_entries(&mut self, entries: Vec<KeyValue>) {
+        //File handle of the segment currently being appended to, together with the start of that segment
+        let mut open_file: Option<(u64, File)> = None;
+        for entry in entries {
+            let absolute_index = self.metadata.log_start + self.log.len() as u64;
+            if self.segments.last().map_or(true, |segment| segment.offsets.len() >= SEGMENT_ENTRIES) {
+                let path = self.segment_path(absolute_index);
+                self.segments.push(Segment { start: absolute_index, path, offsets: vec![], size: 0 });
+            }
+            let segment = self.segments.last_mut().unwrap();
+            if open_file.as_ref().map_or(true, |(start, _)| *start != segment.start) {
+                //Make the previous segment durable before moving on to the next one
+                if let Some((_, file)) = open_file.take() {
+                    file.sync_data().expect("ERROR: Could not sync log segment");
+                }
+                let file = OpenOptions::new().create(true).append(true).open(&segment.path).expect("ERROR: Could not open log segment");
+                open_file = Some((segment.start, file));
+            }
+            let bytes = bincode::serialize(&entry).unwrap();
+            let mut record = (bytes.len() as u32).to_le_bytes().to_vec();
+            record.extend_from_slice(&bytes);
+            open_file.as_mut().unwrap().1.write_all(&record).expect("ERROR: Could not append to log segment");
+            segment.offsets.push(segment.size);
+            segment.size += record.len() as u64;
+            self.log.push(entry);
+        }
+        //Make the appended entries durable before reporting the new log length
+        if let Some((_, file)) = open_file {
+            file.sync_data().expect("ERROR: Could not sync log segment");
+        }
+    }
+
+    //Remove every entry from the (relative) index from_idx onwards, both in memory and on disk
+    fn truncate_log(&mut self, from_idx: u64) {
+        if from_idx >= self.log.len() as u64 {
+            return;
+        }
+        let absolute_index = self.metadata.log_start + from_idx;
+        while let Some(segment) = self.segments.last_mut() {
+            if segment.start >= absolute_index {
+                fs::remove_file(&segment.path).expect("ERROR: Could not remove log segment");
+                self.segments.pop();
+            } else {
+                let keep = (absolute_index - segment.start) as usize;
+                if keep < segment.offsets.len() {
+                    segment.size = segment.offsets[keep];
+                    segment.offsets.truncate(keep);
+                    OpenOptions::new().write(true).open(&segment.path)
+                        .and_then(|file| { file.set_len(segment.size)?; file.sync_all() })
+                        .expect("ERROR: Could not truncate log segment");
+                }
+                break;
+            }
+        }
+        self.log.truncate(from_idx as usize);
+    }
+}
+
+impl Storage<KeyValue, ()> for FileStorage {
+    fn append_entry(&mut self, entry: KeyValue) -> u64 {
+        self.write_entries(vec![entry]);
+        self.get_log_len()
+    }
+
+    fn append_entries(&mut self, entries: Vec<KeyValue>) -> u64 {
+        self.write_entries(entries);
+        self.get_log_len()
+    }
+
+    fn append_on_prefix(&mut self, from_idx: u64, entries: Vec<KeyValue>) -> u64 {
+        self.truncate_log(from_idx);
+        self.write_entries(entries);
+        self.get_log_len()
+    }
+
+    fn set_promise(&mut self, n_prom: Ballot) {
+        self.metadata.promise = n_prom;
+        self.persist_metadata();
+    }
+
+    fn set_decided_idx(&mut self, ld: u64) {
+        self.metadata.decided_idx = ld;
+        self.persist_metadata();
+    }
+
+    fn get_decided_idx(&self) -> u64 {
+        self.metadata.decided_idx
+    }
+
+    fn set_accepted_round(&mut self, na: Ballot) {
+        self.metadata.accepted_round = na;
+        self.persist_metadata();
+    }
+
+    fn get_accepted_round(&self) -> Ballot {
+        self.metadata.accepted_round
+    }
+
+    fn get_entries(&self, from: u64, to: u64) -> Vec<KeyValue> {
+        match self.log.get(from as usize..to as usize) {
+            Some(entries) => entries.to_vec(),
+            None => vec![],
+        }
+    }
+
+    fn get_log_len(&self) -> u64 {
+        self.log.len() as u64
+    }
+
+    fn get_suffix(&self, from: u64) -> Vec<KeyValue> {
+        match self.log.get(from as usize..) {
+            Some(entries) => entries.to_vec(),
+            None => vec![],
+        }
+    }
+
+    fn get_promise(&self) -> Ballot {
+        self.metadata.promise
+    }
+
+    fn set_stopsign(&mut self, s: StopSignEntry) {
+        self.metadata.stopsign = Some((s.stopsign, s.decided));
+        self.persist_metadata();
+    }
+
+    fn get_stopsign(&self) -> Option<StopSignEntry> {
+        self.metadata.stopsign.clone().map(|(stopsign, decided)| StopSignEntry { stopsign, decided })
+    }
+
+    fn trim(&mut self, idx: u64) {
+        let idx = idx.min(self.log.len() as u64);
+        self.log.drain(0..idx as usize);
+        self.metadata.log_start += idx;
+        self.persist_metadata();
+        //Segments that only hold trimmed entries are no longer needed
+        let log_start = self.metadata.log_start;
+        self.segments.retain(|segment| {
+            let fully_trimmed = segment.start + segment.offsets.len() as u64 <= log_start;
+            if fully_trimmed {
+                fs::remove_file(&segment.path).expect("ERROR: Could not remove log segment");
+            }
+            !fully_trimmed
+        });
+    }
+
+    fn set_compacted_idx(&mut self, idx: u64) {
+        self.metadata.compacted_idx = idx;
+        self.persist_metadata();
+    }
+
+    fn get_compacted_idx(&self) -> u64 {
+        self.metadata.compacted_idx
+    }
+
+    fn set_snapshot(&mut self, snapshot: ()) {
+        self.metadata.snapshot = Some(snapshot);
+        self.persist_metadata();
+    }
+
+    fn get_snapshot(&self) -> Option<()> {
+        self.metadata.snapshot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Operation, test_support::test_dir};
+
+    fn entry(index: u64) -> KeyValue {
+        KeyValue { key: format!("key{}", index), operation: Operation::Put(index.to_le_bytes().to_vec()), origin: 1, request_id: index }
+    }
+
+    fn keys(entries: &[KeyValue]) -> Vec<String> {
+        entries.iter().map(|entry| entry.key.clone()).collect()
+    }
+
+    #[test]
+    fn reopens_with_the_same_state() {
+        let dir = test_dir("file-storage-reopen");
+        let mut storage = FileStorage::open(&dir);
+        assert!(!storage.has_state());
+        let entries: Vec<KeyValue> = (0..SEGMENT_ENTRIES as u64 + 10).map(entry).collect();
+        assert_eq!(storage.append_entries(entries.clone()), entries.len() as u64);
+        let promise = Ballot { n: 3, priority: 0, pid: 2 };
+        storage.set_promise(promise);
+        storage.set_decided_idx(7);
+        drop(storage);
+
+        let storage = FileStorage::open(&dir);
+        assert!(storage.has_state());
+        assert_eq!(storage.segments.len(), 2);
+        assert_eq!(keys(&storage.get_suffix(0)), keys(&entries));
+        assert_eq!(storage.get_promise(), promise);
+        assert_eq!(storage.get_decided_idx(), 7);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncates_on_append_on_prefix() {
+        let dir = test_dir("file-storage-truncate");
+        let mut storage = FileStorage::open(&dir);
+        storage.append_entries((0..10).map(entry).collect());
+        assert_eq!(storage.append_on_prefix(5, vec![entry(100), entry(101)]), 7);
+        drop(storage);
+
+        let storage = FileStorage::open(&dir);
+        assert_eq!(keys(&storage.get_suffix(0)), ["key0", "key1", "key2", "key3", "key4", "key100", "key101"]);
+        assert_eq!(keys(&storage.get_entries(4, 6)), ["key4", "key100"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn trims_and_removes_segments() {
+        let dir = test_dir("file-storage-trim");
+        let mut storage = FileStorage::open(&dir);
+        let total = SEGMENT_ENTRIES as u64 + 5;
+        storage.append_entries((0..total).map(entry).collect());
+        let first_segment = storage.segment_path(0);
+        storage.trim(SEGMENT_ENTRIES as u64 + 1);
+        assert!(!first_segment.exists());
+        assert_eq!(storage.get_log_len(), 4);
+        drop(storage);
+
+        let mut storage = FileStorage::open(&dir);
+        assert_eq!(keys(&storage.get_suffix(0)), keys(&(SEGMENT_ENTRIES as u64 + 1..total).map(entry).collect::<Vec<_>>()));
+        //Trimming part of a segment keeps the segment, and the entries before the trim stay skipped
+        storage.trim(2);
+        drop(storage);
+        let storage = FileStorage::open(&dir);
+        assert_eq!(storage.get_log_len(), 2);
+        assert_eq!(storage.get_entries(0, 1)[0].key, format!("key{}", total - 2));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn discards_an_interrupted_write() {
+        let dir = test_dir("file-storage-recovery");
+        let mut storage = FileStorage::open(&dir);
+        storage.append_entries((0..3).map(entry).collect());
+        let segment = storage.segment_path(0);
+        let size = fs::metadata(&segment).unwrap().len();
+        drop(storage);
+        //A record whose length says more than was written before the crash
+        let mut file = OpenOptions::new().append(true).open(&segment).unwrap();
+        file.write_all(&[100, 0, 0, 0, 1, 2]).unwrap();
+        drop(file);
+
+        let mut storage = FileStorage::open(&dir);
+        assert_eq!(storage.get_log_len(), 3);
+        assert_eq!(fs::metadata(&segment).unwrap().len(), size);
+        storage.append_entry(entry(3));
+        drop(storage);
+        let storage = FileStorage::open(&dir);
+        assert_eq!(keys(&storage.get_suffix(0)), ["key0", "key1", "key2", "key3"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
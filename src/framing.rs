@@ -0,0 +1,165 @@
+//Imports
+//Tokio - used for reading from and writing to the connections
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+//Every frame on a connection is a four byte (big-endian) length followed by that many bytes of payload
+//Default upper limit on the payload of a single frame - larger frames are treated as a broken connection
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+//The write_frame function sends one message as a single length-prefixed frame
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    //Write the length and payload together so that a frame is never interleaved with another write
+    writer.write_all(&encode_frame(payload)?).await
+}
+
+//The read_frame function reads one complete frame, however many reads that takes
+//Returns None when the other side closed the connection cleanly between two frames
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R, max_frame_size: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut length_bytes = [0; 4];
+    //A connection closed before any of the length was read is a clean end of stream
+    let n = reader.read(&mut length_bytes).await?;
+    if n == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut length_bytes[n..]).await?;
+    let mut payload = vec![0; frame_length(length_bytes, max_frame_size)?];
+    reader.read_exact(&mut payload).await?;
+    Ok(Some(payload))
+}
+
+//The write_frame_blocking function is write_frame for callers that use std's blocking connections instead of tokio
+pub fn write_frame_blocking<W: std::io::Write>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    writer.write_all(&encode_frame(payload)?)?;
+    writer.flush()
+}
+
+//The read_frame_blocking function is read_frame for std's blocking connections
+pub fn read_frame_blocking<R: std::io::Read>(reader: &mut R, max_frame_size: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut length_bytes = [0; 4];
+    let n = reader.read(&mut length_bytes)?;
+    if n == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut length_bytes[n..])?;
+    let mut payload = vec![0; frame_length(length_bytes, max_frame_size)?];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+//The encode_frame function puts the length in front of the payload
+fn encode_frame(payload: &[u8]) -> io::Result<Vec<u8>> {
+    let length: u32 = payload.len().try_into().map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame too large"))?;
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&length.to_be_bytes());
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+//The frame_length function reads the length of a frame's payload
+fn frame_length(length_bytes: [u8; 4], max_frame_size: usize) -> io::Result<usize> {
+    let length = u32::from_be_bytes(length_bytes) as usize;
+    //Error handling - refuse frames above the limit rather than allocating whatever the length says
+    if length > max_frame_size {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame of {} bytes exceeds the maximum of {} bytes", length, max_frame_size)));
+    }
+    Ok(length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{pin::Pin, task::{Context, Poll}};
+    use tokio::io::ReadBuf;
+
+    //A connection that hands out at most a few bytes per read, so that frames arrive split across reads
+    struct Trickle {
+        bytes: Vec<u8>,
+        position: usize,
+        chunk: usize,
+    }
+
+    impl Trickle {
+        fn new(bytes: Vec<u8>, chunk: usize) -> Trickle {
+            Trickle { bytes, position: 0, chunk }
+        }
+
+        fn next_chunk(&mut self, limit: usize) -> &[u8] {
+            let end = self.bytes.len().min(self.position + self.chunk.min(limit));
+            let start = self.position;
+            self.position = end;
+            &self.bytes[start..end]
+        }
+    }
+
+    impl std::io::Read for Trickle {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            let chunk = self.next_chunk(buffer.len());
+            buffer[..chunk.len()].copy_from_slice(chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    impl AsyncRead for Trickle {
+        fn poll_read(self: Pin<&mut Self>, _: &mut Context<'_>, buffer: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            let limit = buffer.remaining();
+            buffer.put_slice(self.get_mut().next_chunk(limit));
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn frames(payloads: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = vec![];
+        for payload in payloads {
+            write_frame_blocking(&mut bytes, payload).unwrap();
+        }
+        bytes
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let mut bytes = vec![];
+        write_frame(&mut bytes, b"hello").await.unwrap();
+        write_frame(&mut bytes, b"").await.unwrap();
+        assert_eq!(bytes, frames(&[b"hello", b""]));
+        let mut reader = &bytes[..];
+        assert_eq!(read_frame(&mut reader, 5).await.unwrap(), Some(b"hello".to_vec()));
+        assert_eq!(read_frame(&mut reader, 5).await.unwrap(), Some(vec![]));
+        assert_eq!(read_frame(&mut reader, 5).await.unwrap(), None);
+        let mut reader = &bytes[..];
+        assert_eq!(read_frame_blocking(&mut reader, 5).unwrap(), Some(b"hello".to_vec()));
+        assert_eq!(read_frame_blocking(&mut reader, 5).unwrap(), Some(vec![]));
+        assert_eq!(read_frame_blocking(&mut reader, 5).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_oversize_frames() {
+        let bytes = frames(&[b"hello"]);
+        let error = read_frame(&mut &bytes[..], 4).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let error = read_frame_blocking(&mut &bytes[..], 4).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        //The length alone is refused, before the payload has even arrived
+        let length_only = u32::MAX.to_be_bytes();
+        assert_eq!(read_frame(&mut &length_only[..], DEFAULT_MAX_FRAME_SIZE).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(read_frame_blocking(&mut &length_only[..], DEFAULT_MAX_FRAME_SIZE).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn reads_frames_split_across_reads() {
+        let bytes = frames(&[b"split across reads", b"and another"]);
+        for chunk in 1..5 {
+            let mut reader = Trickle::new(bytes.clone(), chunk);
+            assert_eq!(read_frame(&mut reader, 64).await.unwrap(), Some(b"split across reads".to_vec()));
+            assert_eq!(read_frame(&mut reader, 64).await.unwrap(), Some(b"and another".to_vec()));
+            assert_eq!(read_frame(&mut reader, 64).await.unwrap(), None);
+            let mut reader = Trickle::new(bytes.clone(), chunk);
+            assert_eq!(read_frame_blocking(&mut reader, 64).unwrap(), Some(b"split across reads".to_vec()));
+            assert_eq!(read_frame_blocking(&mut reader, 64).unwrap(), Some(b"and another".to_vec()));
+            assert_eq!(read_frame_blocking(&mut reader, 64).unwrap(), None);
+        }
+        //A connection closed in the middle of a frame is an error, not a clean end of stream
+        let cut = bytes[..6].to_vec();
+        assert_eq!(read_frame(&mut Trickle::new(cut.clone(), 1), 64).await.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(read_frame_blocking(&mut Trickle::new(cut, 1), 64).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,429 @@
+//Imports
+//Length-prefixed framing, the same as the nodes use
+use crate::framing::{read_frame, write_frame, DEFAULT_MAX_FRAME_SIZE};
+//Keys are quoted when they contain spaces, and values are written in hex
+use crate::{
+    encoding::{from_hex, to_hex},
+    words::{quote, split_words},
+};
+//Tokio - used for the connection to the node and for waiting on responses
+use tokio::{
+    io::{self, WriteHalf},
+    net::TcpStream,
+    sync::{oneshot, Mutex as AsyncMutex},
+    time::timeout,
+};
+//Used for the requests waiting for a response and for the error type
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex, atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}},
+    time::Duration,
+};
+
+//A blocking client with the same operations, for programs without an async runtime
+pub mod blocking;
+
+//How long connecting to a node may take
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+//How long a request may wait for its response - a little longer than the nodes wait for a write to be decided, so that their own timeout is reported instead
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+//The KvError enum is everything that can go wrong with a request
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KvError {
+    //The node could not be reached, or the connection to it broke
+    Connection(String),
+    //No response arrived in time - the request may or may not have taken effect
+    Timeout,
+    //A compare-and-swap was decided, but the key's value was not the expected one - current is the actual value (None if the key does not exist)
+    ConditionFailed { index: u64, current: Option<Vec<u8>> },
+    //The key holds a different type than the operation is for, so it was left unchanged - holds the key's type
+    WrongType(String),
+    //The node could not carry out the request, for the reason it gave
+    Node(String),
+    //The node sent a response the client does not understand
+    Protocol(String),
+}
+
+impl fmt::Display for KvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KvError::Connection(reason) => write!(f, "connection to the node failed - {}", reason),
+            KvError::Timeout => write!(f, "timed out waiting for the node"),
+            KvError::ConditionFailed { index, current: Some(current) } => write!(f, "decided at log index {} but not applied - the current value is {:?}", index, String::from_utf8_lossy(current)),
+            KvError::ConditionFailed { index, current: None } => write!(f, "decided at log index {} but not applied - the key does not exist", index),
+            KvError::WrongType(kind) => write!(f, "the key holds a {}", kind),
+            KvError::Node(reason) => write!(f, "the node could not carry out the request - {}", reason),
+            KvError::Protocol(response) => write!(f, "unexpected response {:?}", response),
+        }
+    }
+}
+
+impl std::error::Error for KvError {}
+
+//How a get is answered - see the node's README for what each of them guarantees
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Consistency {
+    Local,
+    Linearizable,
+    //From the node if it is at most this many decided entries behind the leader
+    StaleEntries(u64),
+    //From the node if it had applied everything the leader had decided at most this many milliseconds ago
+    StaleMilliseconds(u64),
+}
+
+//A condition on a key that a transaction checks before it writes anything
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Guard<'a> {
+    //The key holds exactly this value
+    Equals(&'a str, &'a [u8]),
+    //The key does not hold this value - also true if the key does not exist
+    NotEquals(&'a str, &'a [u8]),
+    Exists(&'a str),
+    Absent(&'a str),
+}
+
+//A write that is part of a transaction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransactionWrite<'a> {
+    Put(&'a str, &'a [u8]),
+    Delete(&'a str),
+}
+
+//A response from a node, with the request id already removed
+pub(crate) enum Response {
+    //A write was decided at the log index
+    Ack(u64),
+    //The result of a get
+    Value(Option<Vec<u8>>),
+    ConditionFailed { index: u64, current: Option<Vec<u8>> },
+    //The length of a list after a push, and the value taken off a list by a pop
+    Length(u64),
+    Popped(Option<Vec<u8>>),
+    //The members of a set
+    Members(Vec<Vec<u8>>),
+    //A transaction was decided at the log index - and whether its guards held
+    Transaction { index: u64, passed: bool },
+    //Anything else the node answers, e.g. to counters
+    Other(String),
+}
+
+//The request function gives the text of a request, without the request id
+pub(crate) fn request(command: &str, key: &str, arguments: &[String]) -> String {
+    let mut words = vec![command.to_string(), quote(key)];
+    words.extend_from_slice(arguments);
+    words.join(" ")
+}
+
+//The transaction_request function gives the text of a transaction, without the request id
+pub(crate) fn transaction_request(guards: &[Guard], writes: &[TransactionWrite]) -> String {
+    let mut words = vec!["txn".to_string()];
+    for guard in guards {
+        match guard {
+            Guard::Equals(key, value) => words.extend(["if".to_string(), quote(key), "==".to_string(), value_argument(value)]),
+            Guard::NotEquals(key, value) => words.extend(["if".to_string(), quote(key), "!=".to_string(), value_argument(value)]),
+            Guard::Exists(key) => words.extend(["if".to_string(), quote(key), "exists".to_string()]),
+            Guard::Absent(key) => words.extend(["if".to_string(), quote(key), "absent".to_string()]),
+        }
+    }
+    for write in writes {
+        match write {
+            TransactionWrite::Put(key, value) => words.extend(["put".to_string(), quote(key), value_argument(value)]),
+            TransactionWrite::Delete(key) => words.extend(["delete".to_string(), quote(key)]),
+        }
+    }
+    words.join(" ")
+}
+
+//The value_argument function writes a value the way the node expects it
+pub(crate) fn value_argument(value: &[u8]) -> String {
+    quote(&to_hex(value))
+}
+
+//The consistency_argument function writes a consistency the way the node's get command expects it
+pub(crate) fn consistency_argument(consistency: Consistency) -> String {
+    match consistency {
+        Consistency::Local => "local".to_string(),
+        Consistency::Linearizable => "linearizable".to_string(),
+        Consistency::StaleEntries(entries) => format!("stale({})", entries),
+        Consistency::StaleMilliseconds(milliseconds) => format!("stale({}ms)", milliseconds),
+    }
+}
+
+//The parse_response function reads a node's response; errors reported by the node become a KvError
+pub(crate) fn parse_response(response: &str) -> Result<Response, KvError> {
+    let words = split_words(response).map_err(|_| KvError::Protocol(response.to_string()))?;
+    let number = |position: usize| -> Result<u64, KvError> {
+        words.get(position).and_then(|word| word.parse().ok()).ok_or_else(|| KvError::Protocol(response.to_string()))
+    };
+    let bytes = |position: usize| -> Result<Vec<u8>, KvError> {
+        words.get(position).and_then(|word| from_hex(word).ok()).ok_or_else(|| KvError::Protocol(response.to_string()))
+    };
+    let value_or_absent = |position: usize| -> Result<Option<Vec<u8>>, KvError> {
+        match words.get(position).map(|word| word.as_str()) {
+            Some("absent") => Ok(None),
+            _ => bytes(position).map(Some),
+        }
+    };
+    match words.first().map(|word| word.as_str()).unwrap_or_default() {
+        "ack" => Ok(Response::Ack(number(1)?)),
+        "value" => Ok(Response::Value(Some(bytes(2)?))),
+        "not-found" => Ok(Response::Value(None)),
+        "condition-failed" => Ok(Response::ConditionFailed { index: number(1)?, current: value_or_absent(2)? }),
+        "length" => Ok(Response::Length(number(2)?)),
+        "popped" => Ok(Response::Popped(value_or_absent(2)?)),
+        "members" => Ok(Response::Members((2..words.len()).map(bytes).collect::<Result<_, _>>()?)),
+        "txn" => Ok(Response::Transaction { index: number(1)?, passed: words.get(2).map(|word| word.as_str()) == Some("passed") }),
+        //Reads report only the type, writes also the log index they were decided at
+        "wrong-type" => Err(KvError::WrongType(words[words.len() - 1].clone())),
+        "error" => Err(KvError::Node(words[1..].join(" "))),
+        _ => Ok(Response::Other(response.to_string())),
+    }
+}
+
+//The written_at, value, length, popped, members and transaction functions turn a response into the result of the request it answers
+pub(crate) fn written_at(response: Response) -> Result<u64, KvError> {
+    match response {
+        Response::Ack(index) => Ok(index),
+        Response::ConditionFailed { index, current } => Err(KvError::ConditionFailed { index, current }),
+        other => Err(unexpected(other)),
+    }
+}
+
+pub(crate) fn value(response: Response) -> Result<Option<Vec<u8>>, KvError> {
+    match response {
+        Response::Value(value) => Ok(value),
+        other => Err(unexpected(other)),
+    }
+}
+
+pub(crate) fn length(response: Response) -> Result<u64, KvError> {
+    match response {
+        Response::Length(length) => Ok(length),
+        other => Err(unexpected(other)),
+    }
+}
+
+pub(crate) fn popped(response: Response) -> Result<Option<Vec<u8>>, KvError> {
+    match response {
+        Response::Popped(value) => Ok(value),
+        other => Err(unexpected(other)),
+    }
+}
+
+pub(crate) fn members(response: Response) -> Result<Vec<Vec<u8>>, KvError> {
+    match response {
+        Response::Members(members) => Ok(members),
+        other => Err(unexpected(other)),
+    }
+}
+
+pub(crate) fn transaction(response: Response) -> Result<bool, KvError> {
+    match response {
+        Response::Transaction { passed, .. } => Ok(passed),
+        other => Err(unexpected(other)),
+    }
+}
+
+//The unexpected function is the error for a response that does not answer the request it was matched with
+fn unexpected(response: Response) -> KvError {
+    match response {
+        Response::Ack(index) => KvError::Protocol(format!("ack {}", index)),
+        Response::Value(_) => KvError::Protocol("value".to_string()),
+        Response::ConditionFailed { index, .. } => KvError::Protocol(format!("condition-failed {}", index)),
+        Response::Length(length) => KvError::Protocol(format!("length {}", length)),
+        Response::Popped(_) => KvError::Protocol("popped".to_string()),
+        Response::Members(_) => KvError::Protocol("members".to_string()),
+        Response::Transaction { index, .. } => KvError::Protocol(format!("txn {}", index)),
+        Response::Other(response) => KvError::Protocol(response),
+    }
+}
+
+//Requests waiting for their response, by request id
+type Waiting = Arc<Mutex<HashMap<u64, oneshot::Sender<Result<String, KvError>>>>>;
+
+//The KvClient struct is an async client for one node, for embedding the key-value store in other programs
+//Requests can be sent concurrently from several tasks over the one connection; each one waits for the response with its request id
+//Writes and linearizable gets can be sent to any node, which forwards them to the leader
+pub struct KvClient {
+    writer: AsyncMutex<WriteHalf<TcpStream>>,
+    waiting: Waiting,
+    //Set once the node has closed the connection - later requests fail right away
+    closed: Arc<AtomicBool>,
+    //Largest response accepted from the node - shared with the task reading the responses
+    max_frame_size: Arc<AtomicUsize>,
+    next_request: AtomicU64,
+    request_timeout: Duration,
+}
+
+impl KvClient {
+    //Connect to a node at its client address (see config::NodeAddress::client_address)
+    pub async fn connect(address: &str) -> Result<KvClient, KvError> {
+        let stream = match timeout(CONNECT_TIMEOUT, TcpStream::connect(address)).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(error)) => return Err(KvError::Connection(error.to_string())),
+            Err(_) => return Err(KvError::Timeout),
+        };
+        let _ = stream.set_nodelay(true);
+        let (mut reader, writer) = io::split(stream);
+        let waiting: Waiting = Arc::default();
+        let closed = Arc::new(AtomicBool::new(false));
+        let max_frame_size = Arc::new(AtomicUsize::new(DEFAULT_MAX_FRAME_SIZE));
+        let waiting_x = waiting.clone();
+        let closed_x = closed.clone();
+        let max_frame_size_x = max_frame_size.clone();
+        //Hand every response to the request waiting for it
+        tokio::spawn(async move {
+            let reason = loop {
+                let frame = match read_frame(&mut reader, max_frame_size_x.load(Ordering::Relaxed)).await {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => break "the node closed the connection".to_string(),
+                    Err(error) => break error.to_string(),
+                };
+                let response: String = match bincode::deserialize(&frame) {
+                    Ok(response) => response,
+                    Err(error) => break error.to_string(),
+                };
+                if let Some((request_id, response)) = response.split_once(' ') {
+                    if let Some(sender) = request_id.parse().ok().and_then(|request_id: u64| waiting_x.lock().unwrap().remove(&request_id)) {
+                        let _ = sender.send(Ok(response.to_string()));
+                    }
+                }
+            };
+            closed_x.store(true, Ordering::SeqCst);
+            for (_, sender) in waiting_x.lock().unwrap().drain() {
+                let _ = sender.send(Err(KvError::Connection(reason.clone())));
+            }
+        });
+        Ok(KvClient {
+            writer: AsyncMutex::new(writer),
+            waiting,
+            closed,
+            max_frame_size,
+            next_request: AtomicU64::new(0),
+            request_timeout: REQUEST_TIMEOUT,
+        })
+    }
+
+    //Change how long a request may wait for its response
+    pub fn set_request_timeout(&mut self, request_timeout: Duration) {
+        self.request_timeout = request_timeout;
+    }
+
+    //Change the largest response accepted from the node - it should match the node's --max-frame-size
+    pub fn set_max_frame_size(&mut self, max_frame_size: usize) {
+        self.max_frame_size.store(max_frame_size, Ordering::Relaxed);
+    }
+
+    //Set the key to the value; returns the log index the write was decided at
+    pub async fn put(&self, key: &str, value: &[u8]) -> Result<u64, KvError> {
+        let response = self.send(request("put", key, &[value_argument(value)])).await?;
+        written_at(parse_response(&response)?)
+    }
+
+    //Look up the key from whatever the node has decided so far; None if the key does not exist
+    pub async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, KvError> {
+        self.get_with(key, Consistency::Local).await
+    }
+
+    //Look up the key with the given consistency
+    pub async fn get_with(&self, key: &str, consistency: Consistency) -> Result<Option<Vec<u8>>, KvError> {
+        let response = self.send(request("get", key, &[consistency_argument(consistency)])).await?;
+        value(parse_response(&response)?)
+    }
+
+    //Remove the key; returns the log index the delete was decided at
+    pub async fn delete(&self, key: &str) -> Result<u64, KvError> {
+        let response = self.send(request("delete", key, &[])).await?;
+        written_at(parse_response(&response)?)
+    }
+
+    //Set the key to new if its value is expected; returns the log index, or KvError::ConditionFailed with the actual value
+    pub async fn cas(&self, key: &str, expected: &[u8], new: &[u8]) -> Result<u64, KvError> {
+        let response = self.send(request("cas", key, &[value_argument(expected), value_argument(new)])).await?;
+        written_at(parse_response(&response)?)
+    }
+
+    //Add the value to the front (lpush) or back (rpush) of the list; returns the length of the list afterwards
+    pub async fn lpush(&self, key: &str, value: &[u8]) -> Result<u64, KvError> {
+        let response = self.send(request("lpush", key, &[value_argument(value)])).await?;
+        length(parse_response(&response)?)
+    }
+
+    pub async fn rpush(&self, key: &str, value: &[u8]) -> Result<u64, KvError> {
+        let response = self.send(request("rpush", key, &[value_argument(value)])).await?;
+        length(parse_response(&response)?)
+    }
+
+    //Take the value off the front (lpop) or back (rpop) of the list; None if the list is empty
+    pub async fn lpop(&self, key: &str) -> Result<Option<Vec<u8>>, KvError> {
+        let response = self.send(request("lpop", key, &[])).await?;
+        popped(parse_response(&response)?)
+    }
+
+    pub async fn rpop(&self, key: &str) -> Result<Option<Vec<u8>>, KvError> {
+        let response = self.send(request("rpop", key, &[])).await?;
+        popped(parse_response(&response)?)
+    }
+
+    //Add the member to (sadd) or remove it from (srem) the set; returns the log index the write was decided at
+    pub async fn sadd(&self, key: &str, member: &[u8]) -> Result<u64, KvError> {
+        let response = self.send(request("sadd", key, &[value_argument(member)])).await?;
+        written_at(parse_response(&response)?)
+    }
+
+    pub async fn srem(&self, key: &str, member: &[u8]) -> Result<u64, KvError> {
+        let response = self.send(request("srem", key, &[value_argument(member)])).await?;
+        written_at(parse_response(&response)?)
+    }
+
+    //The members of the set, in order; empty if the key does not exist
+    pub async fn smembers(&self, key: &str) -> Result<Vec<Vec<u8>>, KvError> {
+        let response = self.send(request("smembers", key, &[])).await?;
+        members(parse_response(&response)?)
+    }
+
+    //Set the field of the hash to the value; returns the log index the write was decided at
+    pub async fn hset(&self, key: &str, field: &str, value: &[u8]) -> Result<u64, KvError> {
+        let response = self.send(request("hset", key, &[quote(field), value_argument(value)])).await?;
+        written_at(parse_response(&response)?)
+    }
+
+    //Look up the field of the hash; None if the key or the field does not exist
+    pub async fn hget(&self, key: &str, field: &str) -> Result<Option<Vec<u8>>, KvError> {
+        let response = self.send(request("hget", key, &[quote(field)])).await?;
+        value(parse_response(&response)?)
+    }
+
+    //Check the guards and, only if all of them hold, carry out the writes - all as one entry in the log, so that no other write comes in between
+    //Returns whether the guards held
+    pub async fn txn(&self, guards: &[Guard<'_>], writes: &[TransactionWrite<'_>]) -> Result<bool, KvError> {
+        let response = self.send(transaction_request(guards, writes)).await?;
+        transaction(parse_response(&response)?)
+    }
+
+    //Send a request and wait for its response
+    async fn send(&self, request: String) -> Result<String, KvError> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(KvError::Connection("the node closed the connection".to_string()));
+        }
+        let request_id = self.next_request.fetch_add(1, Ordering::Relaxed) + 1;
+        let (sender, receiver) = oneshot::channel();
+        self.waiting.lock().unwrap().insert(request_id, sender);
+        let encrypted_message: Vec<u8> = bincode::serialize(&format!("{} {}", request_id, request)).unwrap();
+        let written = write_frame(&mut *self.writer.lock().await, &encrypted_message).await;
+        if let Err(error) = written {
+            self.waiting.lock().unwrap().remove(&request_id);
+            return Err(KvError::Connection(error.to_string()));
+        }
+        match timeout(self.request_timeout, receiver).await {
+            Ok(Ok(response)) => response,
+            Ok(Err(_)) => Err(KvError::Connection("the node closed the connection".to_string())),
+            Err(_) => {
+                self.waiting.lock().unwrap().remove(&request_id);
+                Err(KvError::Timeout)
+            },
+        }
+    }
+}
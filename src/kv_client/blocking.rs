@@ -0,0 +1,238 @@
+//Imports
+//The request and response handling shared with the async client
+use super::{consistency_argument, length, members, parse_response, popped, request, transaction, transaction_request, value, value_argument, written_at, Consistency, Guard, KvError, TransactionWrite, CONNECT_TIMEOUT, REQUEST_TIMEOUT};
+//Hash fields are quoted the same way as keys
+use crate::words::quote;
+//Length-prefixed framing, the same as the nodes use
+use crate::framing::{read_frame_blocking, write_frame_blocking, DEFAULT_MAX_FRAME_SIZE};
+//Std networking - no async runtime is needed
+use std::{
+    io,
+    net::{TcpStream, ToSocketAddrs},
+    thread,
+    time::{Duration, Instant},
+};
+
+//How many times a failed request is tried again by default, and how long to wait in between
+pub const DEFAULT_RETRIES: u32 = 2;
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_millis(200);
+
+//The KvClient struct is a blocking client for one node, for programs that do not run an async runtime
+//It speaks the same protocol as the async client, one request at a time; a broken connection is reopened on the next attempt
+//Reads are tried again after any failure, but writes only if they never reached the node - a write that timed out may still be decided, and sending it again could apply it twice or undo a later write
+pub struct KvClient {
+    address: String,
+    stream: Option<TcpStream>,
+    next_request: u64,
+    connect_timeout: Duration,
+    request_timeout: Duration,
+    retries: u32,
+    retry_delay: Duration,
+    max_frame_size: usize,
+}
+
+impl KvClient {
+    //Connect to a node at its client address (see config::NodeAddress::client_address)
+    pub fn connect(address: &str) -> Result<KvClient, KvError> {
+        let mut client = KvClient {
+            address: address.to_string(),
+            stream: None,
+            next_request: 0,
+            connect_timeout: CONNECT_TIMEOUT,
+            request_timeout: REQUEST_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+            retry_delay: DEFAULT_RETRY_DELAY,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        };
+        client.stream = Some(client.open()?);
+        Ok(client)
+    }
+
+    pub fn set_connect_timeout(&mut self, connect_timeout: Duration) {
+        self.connect_timeout = connect_timeout;
+    }
+
+    pub fn set_request_timeout(&mut self, request_timeout: Duration) {
+        self.request_timeout = request_timeout;
+    }
+
+    //Change how many times a failed request is tried again, and how long to wait in between
+    pub fn set_retries(&mut self, retries: u32, retry_delay: Duration) {
+        self.retries = retries;
+        self.retry_delay = retry_delay;
+    }
+
+    //Change the largest response accepted from the node - it should match the node's --max-frame-size
+    pub fn set_max_frame_size(&mut self, max_frame_size: usize) {
+        self.max_frame_size = max_frame_size;
+    }
+
+    //Set the key to the value; returns the log index the write was decided at
+    pub fn put(&mut self, key: &str, value: &[u8]) -> Result<u64, KvError> {
+        let response = self.send(request("put", key, &[value_argument(value)]), false)?;
+        written_at(parse_response(&response)?)
+    }
+
+    //Look up the key from whatever the node has decided so far; None if the key does not exist
+    pub fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>, KvError> {
+        self.get_with(key, Consistency::Local)
+    }
+
+    //Look up the key with the given consistency
+    pub fn get_with(&mut self, key: &str, consistency: Consistency) -> Result<Option<Vec<u8>>, KvError> {
+        let response = self.send(request("get", key, &[consistency_argument(consistency)]), true)?;
+        value(parse_response(&response)?)
+    }
+
+    //Remove the key; returns the log index the delete was decided at
+    pub fn delete(&mut self, key: &str) -> Result<u64, KvError> {
+        let response = self.send(request("delete", key, &[]), false)?;
+        written_at(parse_response(&response)?)
+    }
+
+    //Set the key to new if its value is expected; returns the log index, or KvError::ConditionFailed with the actual value
+    pub fn cas(&mut self, key: &str, expected: &[u8], new: &[u8]) -> Result<u64, KvError> {
+        let response = self.send(request("cas", key, &[value_argument(expected), value_argument(new)]), false)?;
+        written_at(parse_response(&response)?)
+    }
+
+    //Add the value to the front (lpush) or back (rpush) of the list; returns the length of the list afterwards
+    pub fn lpush(&mut self, key: &str, value: &[u8]) -> Result<u64, KvError> {
+        let response = self.send(request("lpush", key, &[value_argument(value)]), false)?;
+        length(parse_response(&response)?)
+    }
+
+    pub fn rpush(&mut self, key: &str, value: &[u8]) -> Result<u64, KvError> {
+        let response = self.send(request("rpush", key, &[value_argument(value)]), false)?;
+        length(parse_response(&response)?)
+    }
+
+    //Take the value off the front (lpop) or back (rpop) of the list; None if the list is empty
+    pub fn lpop(&mut self, key: &str) -> Result<Option<Vec<u8>>, KvError> {
+        let response = self.send(request("lpop", key, &[]), false)?;
+        popped(parse_response(&response)?)
+    }
+
+    pub fn rpop(&mut self, key: &str) -> Result<Option<Vec<u8>>, KvError> {
+        let response = self.send(request("rpop", key, &[]), false)?;
+        popped(parse_response(&response)?)
+    }
+
+    //Add the member to (sadd) or remove it from (srem) the set; returns the log index the write was decided at
+    pub fn sadd(&mut self, key: &str, member: &[u8]) -> Result<u64, KvError> {
+        let response = self.send(request("sadd", key, &[value_argument(member)]), false)?;
+        written_at(parse_response(&response)?)
+    }
+
+    pub fn srem(&mut self, key: &str, member: &[u8]) -> Result<u64, KvError> {
+        let response = self.send(request("srem", key, &[value_argument(member)]), false)?;
+        written_at(parse_response(&response)?)
+    }
+
+    //The members of the set, in order; empty if the key does not exist
+    pub fn smembers(&mut self, key: &str) -> Result<Vec<Vec<u8>>, KvError> {
+        let response = self.send(request("smembers", key, &[]), true)?;
+        members(parse_response(&response)?)
+    }
+
+    //Set the field of the hash to the value; returns the log index the write was decided at
+    pub fn hset(&mut self, key: &str, field: &str, value: &[u8]) -> Result<u64, KvError> {
+        let response = self.send(request("hset", key, &[quote(field), value_argument(value)]), false)?;
+        written_at(parse_response(&response)?)
+    }
+
+    //Look up the field of the hash; None if the key or the field does not exist
+    pub fn hget(&mut self, key: &str, field: &str) -> Result<Option<Vec<u8>>, KvError> {
+        let response = self.send(request("hget", key, &[quote(field)]), true)?;
+        value(parse_response(&response)?)
+    }
+
+    //Check the guards and, only if all of them hold, carry out the writes - all as one entry in the log, so that no other write comes in between
+    //Returns whether the guards held
+    pub fn txn(&mut self, guards: &[Guard<'_>], writes: &[TransactionWrite<'_>]) -> Result<bool, KvError> {
+        let response = self.send(transaction_request(guards, writes), false)?;
+        transaction(parse_response(&response)?)
+    }
+
+    //Open a new connection to the node, trying every address the name resolves to
+    fn open(&self) -> Result<TcpStream, KvError> {
+        let addresses = self.address.to_socket_addrs().map_err(|error| KvError::Connection(error.to_string()))?;
+        let mut last_error = KvError::Connection(format!("{} did not resolve to any address", self.address));
+        for address in addresses {
+            match TcpStream::connect_timeout(&address, self.connect_timeout) {
+                Ok(stream) => {
+                    let _ = stream.set_nodelay(true);
+                    return Ok(stream);
+                },
+                Err(error) if error.kind() == io::ErrorKind::TimedOut => last_error = KvError::Timeout,
+                Err(error) => last_error = KvError::Connection(error.to_string()),
+            }
+        }
+        Err(last_error)
+    }
+
+    //Send a request and wait for its response, trying again after connection failures and timeouts
+    //A write is only tried again if it could not be sent at all
+    fn send(&mut self, request: String, read: bool) -> Result<String, KvError> {
+        let mut attempt = 0;
+        loop {
+            let (error, sent) = match self.attempt(&request) {
+                Ok(response) => return Ok(response),
+                Err(attempt_error) => attempt_error,
+            };
+            //Errors from the node itself are answers, not failures to reach it
+            let retryable = !matches!(error, KvError::Node(_) | KvError::Protocol(_)) && (read || !sent);
+            if !retryable || attempt >= self.retries {
+                return Err(error);
+            }
+            attempt += 1;
+            thread::sleep(self.retry_delay);
+        }
+    }
+
+    //Send a request once; on failure also tells whether the request may have reached the node
+    fn attempt(&mut self, request: &str) -> Result<String, (KvError, bool)> {
+        if self.stream.is_none() {
+            self.stream = Some(self.open().map_err(|error| (error, false))?);
+        }
+        self.next_request += 1;
+        let request_id = self.next_request;
+        let stream = self.stream.as_mut().unwrap();
+        let encrypted_message: Vec<u8> = bincode::serialize(&format!("{} {}", request_id, request)).unwrap();
+        if let Err(error) = write_frame_blocking(stream, &encrypted_message) {
+            self.stream = None;
+            return Err((KvError::Connection(error.to_string()), true));
+        }
+        //Responses to earlier requests that timed out may still arrive first; they are skipped
+        let deadline = Instant::now() + self.request_timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                self.stream = None;
+                return Err((KvError::Timeout, true));
+            }
+            let _ = stream.set_read_timeout(Some(remaining));
+            let frame = match read_frame_blocking(stream, self.max_frame_size) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => {
+                    self.stream = None;
+                    return Err((KvError::Connection("the node closed the connection".to_string()), true));
+                },
+                Err(error) if matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                    self.stream = None;
+                    return Err((KvError::Timeout, true));
+                },
+                Err(error) => {
+                    self.stream = None;
+                    return Err((KvError::Connection(error.to_string()), true));
+                },
+            };
+            let response: String = bincode::deserialize(&frame).map_err(|error| (KvError::Protocol(error.to_string()), true))?;
+            if let Some((response_id, response)) = response.split_once(' ') {
+                if response_id.parse() == Ok(request_id) {
+                    return Ok(response.to_string());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,164 @@
+//Imports
+//Serde - the maximum lag is sent along with the get it belongs to
+use serde::{Serialize, Deserialize};
+//Used for the reports from the leader
+use std::{collections::VecDeque, time};
+
+//Reports older than this say nothing about how far behind the node is - the leader may be gone
+const REPORT_TIMEOUT: time::Duration = time::Duration::from_millis(500);
+//Number of reports kept while the node catches up; at one report per BLE tick this covers 20 seconds
+const MAX_REPORTS: usize = 1000;
+
+//The MaxLag enum is how far behind the leader a node may be to answer a stale get on its own
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum MaxLag {
+    //At most this many decided entries not yet applied
+    Entries(u64),
+    //Applied everything the leader had decided at most this many milliseconds ago
+    Milliseconds(u64),
+}
+
+impl MaxLag {
+    //Parse the "stale(N)" or "stale(Nms)" consistency of a get
+    pub fn parse(consistency: &str) -> Option<MaxLag> {
+        let max_lag = consistency.strip_prefix("stale(")?.strip_suffix(')')?;
+        match max_lag.strip_suffix("ms") {
+            Some(milliseconds) => milliseconds.parse().ok().map(MaxLag::Milliseconds),
+            None => max_lag.parse().ok().map(MaxLag::Entries),
+        }
+    }
+}
+
+//The LeaderProgress struct tracks how far this node is behind the leader, based on the decided index the leader reports on every BLE tick
+#[derive(Default)]
+pub struct LeaderProgress {
+    //The leader's reports that the node has not caught up with yet - its decided index and when the report arrived
+    reports: VecDeque<(u64, time::Instant)>,
+    //The latest report, also once the node has caught up with it
+    latest: Option<(u64, time::Instant)>,
+    //When the leader had decided nothing that this node has not applied, as far as the node knows
+    caught_up_at: Option<time::Instant>,
+    //How far the leader has trimmed its log, according to its latest report
+    compacted_idx: u64,
+}
+
+impl LeaderProgress {
+    //Record a report from the leader
+    pub fn report(&mut self, decided_idx: u64, compacted_idx: u64) {
+        self.report_at(decided_idx, compacted_idx, time::Instant::now());
+    }
+
+    //report_at and is_within_at are given the time instead of reading the clock, so that tests can replay reports at chosen moments
+    pub fn report_at(&mut self, decided_idx: u64, compacted_idx: u64, now: time::Instant) {
+        self.compacted_idx = compacted_idx;
+        let report = (decided_idx, now);
+        self.reports.push_back(report);
+        if self.reports.len() > MAX_REPORTS {
+            self.reports.pop_front();
+        }
+        self.latest = Some(report);
+    }
+
+    //Whether a node that has applied up to applied_idx is within the maximum lag of the leader
+    //If it is not, the stale get is forwarded to the leader like a linearizable one
+    pub fn is_within(&mut self, max_lag: MaxLag, applied_idx: u64) -> bool {
+        self.is_within_at(max_lag, applied_idx, time::Instant::now())
+    }
+
+    pub fn is_within_at(&mut self, max_lag: MaxLag, applied_idx: u64, now: time::Instant) -> bool {
+        //Every report the node has caught up with moves the time it was last caught up forward
+        while let Some((decided_idx, received)) = self.reports.front().copied() {
+            if decided_idx > applied_idx {
+                break;
+            }
+            self.caught_up_at = Some(received);
+            self.reports.pop_front();
+        }
+        match max_lag {
+            MaxLag::Entries(entries) => match self.latest {
+                Some((decided_idx, received)) => now.duration_since(received) < REPORT_TIMEOUT && decided_idx.saturating_sub(applied_idx) <= entries,
+                None => false,
+            },
+            MaxLag::Milliseconds(milliseconds) => match self.caught_up_at {
+                Some(caught_up_at) => now.duration_since(caught_up_at) <= time::Duration::from_millis(milliseconds),
+                None => false,
+            },
+        }
+    }
+
+    //The leader's compacted index - entries before it can only be had from a snapshot
+    pub fn compacted_idx(&self) -> u64 {
+        self.compacted_idx
+    }
+
+    //Forget everything - the reports came from a leader that is gone
+    pub fn reset(&mut self) {
+        *self = LeaderProgress::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> time::Duration {
+        time::Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn parses_the_maximum_lag() {
+        assert!(matches!(MaxLag::parse("stale(5)"), Some(MaxLag::Entries(5))));
+        assert!(matches!(MaxLag::parse("stale(250ms)"), Some(MaxLag::Milliseconds(250))));
+        assert!(MaxLag::parse("stale()").is_none());
+        assert!(MaxLag::parse("stale(5s)").is_none());
+        assert!(MaxLag::parse("stale(5").is_none());
+    }
+
+    #[test]
+    fn bounds_the_lag_in_entries() {
+        let start = time::Instant::now();
+        let mut progress = LeaderProgress::default();
+        //Without a report the node cannot tell how far behind it is
+        assert!(!progress.is_within_at(MaxLag::Entries(100), 10, start));
+        progress.report_at(15, 0, start);
+        assert!(progress.is_within_at(MaxLag::Entries(5), 10, start));
+        //Exceeding the bound - the get goes to the leader
+        assert!(!progress.is_within_at(MaxLag::Entries(4), 10, start));
+        //An old report says nothing about the leader any more
+        assert!(progress.is_within_at(MaxLag::Entries(5), 10, start + ms(499)));
+        assert!(!progress.is_within_at(MaxLag::Entries(5), 10, start + REPORT_TIMEOUT));
+        progress.report_at(16, 0, start + REPORT_TIMEOUT);
+        assert!(progress.is_within_at(MaxLag::Entries(6), 10, start + REPORT_TIMEOUT));
+    }
+
+    #[test]
+    fn bounds_the_lag_in_milliseconds() {
+        let start = time::Instant::now();
+        let mut progress = LeaderProgress::default();
+        progress.report_at(10, 0, start);
+        progress.report_at(20, 0, start + ms(100));
+        progress.report_at(30, 0, start + ms(200));
+        //Not caught up with any report yet
+        assert!(!progress.is_within_at(MaxLag::Milliseconds(1000), 5, start + ms(200)));
+        //Caught up with what the leader had decided at the second report
+        assert!(progress.is_within_at(MaxLag::Milliseconds(150), 25, start + ms(250)));
+        //Exceeding the bound - the get goes to the leader
+        assert!(!progress.is_within_at(MaxLag::Milliseconds(100), 25, start + ms(250)));
+        //Catching up with the latest report moves the time forward
+        assert!(progress.is_within_at(MaxLag::Milliseconds(50), 30, start + ms(250)));
+    }
+
+    #[test]
+    fn reset_forgets_the_leader() {
+        let start = time::Instant::now();
+        let mut progress = LeaderProgress::default();
+        progress.report_at(10, 4, start);
+        assert!(progress.is_within_at(MaxLag::Entries(0), 10, start));
+        assert!(progress.is_within_at(MaxLag::Milliseconds(0), 10, start));
+        assert_eq!(progress.compacted_idx(), 4);
+        progress.reset();
+        assert!(!progress.is_within_at(MaxLag::Entries(100), 10, start));
+        assert!(!progress.is_within_at(MaxLag::Milliseconds(1000), 10, start));
+        assert_eq!(progress.compacted_idx(), 0);
+    }
+}
@@ -0,0 +1,224 @@
+//Imports
+//OmniPaxos library - the lease is granted to the ballot a leader was elected with
+use omnipaxos_core::ballot_leader_election::Ballot;
+//Used for the rounds in flight and the lease timing
+use std::{collections::{HashMap, HashSet}, time};
+
+//A lease round - when the leader sent its heartbeat, the nodes that have acknowledged it and the leader's decided index at the time
+struct LeaseRound {
+    started: time::Instant,
+    acks: HashSet<u64>,
+    index: u64,
+}
+
+//The Lease struct lets the leader answer linearizable gets on its own, without a quorum round trip per get
+//On every BLE tick the leader sends a lease heartbeat; once a majority (counting itself) has acknowledged it, the leader
+//holds the lease for the lease duration counted from when the heartbeat was sent
+//A node only acknowledges a heartbeat for the ballot it has promised. It then grants the lease to that ballot, and until the lease
+//duration has passed since it received the heartbeat it neither switches to another leader nor hands SequencePaxos a prepare for
+//any other ballot. Any new leader needs promises from a majority, which overlaps with the majority that granted the lease, so no
+//new leader can decide anything while the lease is valid
+//The leader only renews its lease once it has decided an entry of its own ballot, so its decided index covers everything earlier leaders decided
+pub struct Lease {
+    duration: time::Duration,
+    majority: usize,
+    next_round: u64,
+    rounds: HashMap<u64, LeaseRound>,
+    //The lease this node holds as the leader - until when, and the decided index that reads have to wait for
+    valid_until: Option<time::Instant>,
+    index: u64,
+    //The ballot this node has granted the lease to, and until when
+    granted: Option<(Ballot, time::Instant)>,
+}
+
+impl Lease {
+    //A duration of zero turns leases off - every linearizable get then goes through the read index
+    //A node may have granted a lease just before it restarted, so it starts out as if it had granted one to a ballot no leader has
+    pub fn new(duration: time::Duration, number_of_nodes: usize) -> Lease {
+        Lease::new_at(duration, number_of_nodes, time::Instant::now())
+    }
+
+    //The functions ending in _at take the current time from the caller, which lets the tests step through the lease deterministically
+    pub fn new_at(duration: time::Duration, number_of_nodes: usize, now: time::Instant) -> Lease {
+        Lease {
+            duration,
+            majority: number_of_nodes / 2 + 1,
+            next_round: 0,
+            rounds: HashMap::new(),
+            valid_until: None,
+            index: 0,
+            granted: Some((Ballot::default(), now + duration)),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.duration.is_zero()
+    }
+
+    //Start a new lease round at the leader's decided index; returns the round number to send to the peers in the heartbeat
+    pub fn start_round(&mut self, decided_idx: u64) -> u64 {
+        self.start_round_at(decided_idx, time::Instant::now())
+    }
+
+    pub fn start_round_at(&mut self, decided_idx: u64, now: time::Instant) -> u64 {
+        //Rounds older than the lease duration could not extend the lease even if they were acknowledged now
+        let duration = self.duration;
+        self.rounds.retain(|_, lease_round| now.duration_since(lease_round.started) < duration);
+        self.next_round += 1;
+        self.rounds.insert(self.next_round, LeaseRound { started: now, acks: HashSet::new(), index: decided_idx });
+        self.next_round
+    }
+
+    //Record that a node (the leader included) acknowledged a lease round
+    pub fn acknowledge(&mut self, round: u64, from: u64) {
+        let lease_round = match self.rounds.get_mut(&round) {
+            Some(lease_round) => lease_round,
+            None => return,
+        };
+        lease_round.acks.insert(from);
+        if lease_round.acks.len() < self.majority {
+            return;
+        }
+        let until = lease_round.started + self.duration;
+        let extends = match self.valid_until {
+            Some(valid_until) => valid_until < until,
+            None => true,
+        };
+        if extends {
+            self.valid_until = Some(until);
+        }
+        self.index = self.index.max(lease_round.index);
+        //Older rounds can no longer extend the lease
+        self.rounds.retain(|other_round, _| *other_round > round);
+    }
+
+    //The decided index the leader has to have applied before answering from the lease - None if it does not hold a valid lease
+    pub fn read_index(&self) -> Option<u64> {
+        self.read_index_at(time::Instant::now())
+    }
+
+    pub fn read_index_at(&self, now: time::Instant) -> Option<u64> {
+        match self.valid_until {
+            Some(valid_until) if now < valid_until => Some(self.index),
+            _ => None,
+        }
+    }
+
+    //Drop the lease - the node is no longer the leader
+    pub fn revoke(&mut self) {
+        self.rounds.clear();
+        self.valid_until = None;
+        self.index = 0;
+    }
+
+    //Start over in a new configuration with a different number of nodes
+    //A lease granted in the old configuration still holds the node back from switching leaders until it expires
+    pub fn resize(&mut self, number_of_nodes: usize) {
+        self.revoke();
+        self.majority = number_of_nodes / 2 + 1;
+    }
+
+    //Grant the lease to the ballot the node has promised
+    pub fn grant(&mut self, ballot: Ballot) {
+        self.grant_at(ballot, time::Instant::now());
+    }
+
+    pub fn grant_at(&mut self, ballot: Ballot, now: time::Instant) {
+        self.granted = Some((ballot, now + self.duration));
+    }
+
+    //Whether the node may switch to a leader with the given ballot, or promise it, i.e. it has not granted an unexpired lease to a different ballot
+    pub fn allows(&self, ballot: Ballot) -> bool {
+        self.allows_at(ballot, time::Instant::now())
+    }
+
+    pub fn allows_at(&self, ballot: Ballot, now: time::Instant) -> bool {
+        match self.granted {
+            Some((granted_ballot, until)) => granted_ballot == ballot || now >= until,
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DURATION: time::Duration = time::Duration::from_millis(100);
+    const LEADER: Ballot = Ballot { n: 2, priority: 0, pid: 1 };
+    const CHALLENGER: Ballot = Ballot { n: 3, priority: 0, pid: 2 };
+
+    fn ms(millis: u64) -> time::Duration {
+        time::Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn expires_after_the_duration() {
+        let start = time::Instant::now();
+        let mut lease = Lease::new_at(DURATION, 3, start);
+        let round = lease.start_round_at(5, start);
+        lease.acknowledge(round, 1);
+        assert_eq!(lease.read_index_at(start), None);
+        lease.acknowledge(round, 2);
+        //The lease counts from when the heartbeat was sent, not from when the majority answered
+        assert_eq!(lease.read_index_at(start + ms(99)), Some(5));
+        assert_eq!(lease.read_index_at(start + DURATION), None);
+        //A later round extends the lease and moves the read index up
+        let later = lease.start_round_at(8, start + ms(60));
+        lease.acknowledge(later, 1);
+        lease.acknowledge(later, 3);
+        assert_eq!(lease.read_index_at(start + ms(150)), Some(8));
+        assert_eq!(lease.read_index_at(start + ms(160)), None);
+        //Rounds older than the duration are dropped and cannot extend the lease any more
+        let old = lease.start_round_at(9, start + ms(200));
+        let new = lease.start_round_at(9, start + ms(300));
+        lease.acknowledge(old, 1);
+        lease.acknowledge(old, 2);
+        assert_eq!(lease.read_index_at(start + ms(300)), None);
+        lease.acknowledge(new, 1);
+        lease.acknowledge(new, 2);
+        assert_eq!(lease.read_index_at(start + ms(300)), Some(9));
+        lease.revoke();
+        assert_eq!(lease.read_index_at(start + ms(300)), None);
+    }
+
+    #[test]
+    fn resize_changes_the_majority() {
+        let start = time::Instant::now();
+        let mut lease = Lease::new_at(DURATION, 5, start);
+        let round = lease.start_round_at(4, start);
+        lease.acknowledge(round, 1);
+        lease.acknowledge(round, 2);
+        assert_eq!(lease.read_index_at(start), None);
+        lease.acknowledge(round, 3);
+        assert_eq!(lease.read_index_at(start), Some(4));
+        //The lease of the old configuration does not carry over, and two of three nodes are now a majority
+        lease.resize(3);
+        assert_eq!(lease.read_index_at(start), None);
+        let round = lease.start_round_at(6, start + ms(10));
+        lease.acknowledge(round, 1);
+        lease.acknowledge(round, 2);
+        assert_eq!(lease.read_index_at(start + ms(10)), Some(6));
+    }
+
+    #[test]
+    fn holds_back_other_ballots_while_granted() {
+        let start = time::Instant::now();
+        //A restarted node may have granted a lease just before it went down, so it holds back every ballot at first
+        let mut lease = Lease::new_at(DURATION, 3, start);
+        assert!(!lease.allows_at(LEADER, start));
+        assert!(lease.allows_at(LEADER, start + DURATION));
+        lease.grant_at(LEADER, start + ms(200));
+        assert!(lease.allows_at(LEADER, start + ms(250)));
+        assert!(!lease.allows_at(CHALLENGER, start + ms(250)));
+        assert!(!lease.allows_at(CHALLENGER, start + ms(299)));
+        assert!(lease.allows_at(CHALLENGER, start + ms(300)));
+        //The grant outlives a reconfiguration
+        lease.resize(5);
+        assert!(!lease.allows_at(CHALLENGER, start + ms(250)));
+        //With leases turned off nothing is held back
+        let disabled = Lease::new_at(time::Duration::ZERO, 3, start);
+        assert!(!disabled.is_enabled());
+        assert!(disabled.allows_at(CHALLENGER, start));
+    }
+}
@@ -0,0 +1,11 @@
+//Code shared by the node (src/main.rs) and the client (src/bin/client.rs)
+//Framing - how messages are delimited on the TCP connections
+pub mod framing;
+//Cluster configuration - which nodes exist and where they can be reached
+pub mod config;
+//Async client for programs that embed the key-value store
+pub mod kv_client;
+//How requests and responses are split into words, with quotes for words that contain spaces
+pub mod words;
+//How values, which are arbitrary bytes, are written in requests and responses
+pub mod encoding;
@@ -1,88 +1,346 @@
 //Imports
 //OmniPaxos library
 use omnipaxos_core::{
-    sequence_paxos::{SequencePaxos, SequencePaxosConfig},
-    storage::{memory_storage::MemoryStorage},
-    ballot_leader_election::{BallotLeaderElection, BLEConfig, messages::BLEMessage},
-    messages::Message,
-    util::LogEntry::{self, Decided},
+    sequence_paxos::{ReconfigurationRequest, SequencePaxos, SequencePaxosConfig},
+    ballot_leader_election::{Ballot, BallotLeaderElection, BLEConfig, messages::BLEMessage},
+    messages::{Message, PaxosMsg},
+    storage::Storage,
+    util::LogEntry::Decided,
 };
 //Tokio - used for network stuff
 use tokio::{
     net::{TcpListener, TcpStream},
-    io::{self, AsyncReadExt, AsyncWriteExt},
+    io,
     sync::mpsc,
 };
 //StructOpt - used for getting input from the command line
 use structopt::StructOpt;
 //Serde - used for serializing (turning into bytes) and deserializing messages
 use serde::{Serialize, Deserialize};
-//Used for timers
-use std::{thread, time};    
+//Used for timers, the data directory, the writes waiting to be decided and the member pids shared with the client connections
+use std::{thread, time, path::PathBuf, collections::HashMap, sync::{Arc, Mutex}};    
+//The key-value state machine that decided entries are applied to
+mod state_machine;
+use state_machine::{KeyValueStore, Value, WriteResult};
+//Storage for SequencePaxos - in memory or in a data directory on disk, in a versioned format
+mod data_format;
+mod file_storage;
+mod storage;
+use storage::NodeStorage;
+//Long-lived connections to the other nodes
+mod connections;
+use connections::PeerConnections;
+//Registry of the connected clients, used to send responses back on the connection a request came in on
+mod clients;
+use clients::Clients;
+//Messages between the nodes and the leader's bookkeeping for linearizable reads
+mod peer_message;
+use peer_message::PeerMessage;
+mod read_index;
+use read_index::{ReadIndex, ReadWaiter};
+mod lease;
+use lease::Lease;
+mod leader_progress;
+use leader_progress::{LeaderProgress, MaxLag};
+//Snapshots of the key-value store, which let the log be trimmed
+mod snapshot;
+use snapshot::Snapshotter;
+mod snapshot_transfer;
+use snapshot_transfer::{Received, SnapshotTransfer};
+//The configurations the cluster goes through when nodes are added or removed
+mod membership;
+use membership::{Membership, MembershipChange};
+//Helpers shared by the tests of the modules above
+#[cfg(test)]
+mod test_support;
+//Length-prefixed framing and the cluster configuration, shared with the client
+use omnipaxos_key_value_store::{
+    framing::{read_frame, write_frame, DEFAULT_MAX_FRAME_SIZE},
+    config::{ClusterConfig, NodeAddress},
+    words::{quote, split_words},
+    encoding::{from_hex, to_hex},
+};
 
 //Structs for the nodes and the key-value pairs
 #[derive(Debug, Serialize, Deserialize, StructOpt)]
 struct Node {
     #[structopt(long)]
     pid: u64,
+    //The peers default to every other node in the configuration file
     #[structopt(long)]
     peers: Vec<u64>,
+    //Cluster configuration file with the address of every node; without it all nodes are on 127.0.0.1 at the default ports
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+    //Overrides for this node's own entry in the configuration
+    #[structopt(long)]
+    host: Option<String>,
+    #[structopt(long)]
+    sp_port: Option<u16>,
+    #[structopt(long)]
+    ble_port: Option<u16>,
+    #[structopt(long)]
+    client_port: Option<u16>,
+    //Directory where the node persists its log and metadata; without it everything is kept in memory
+    #[structopt(long, parse(from_os_str))]
+    data_dir: Option<PathBuf>,
+    //Largest message (in bytes) accepted on any connection - framing::DEFAULT_MAX_FRAME_SIZE (16 MiB) by default
+    #[structopt(long)]
+    max_frame_size: Option<usize>,
+    //Largest value (in bytes) a write may set - 1 MiB by default
+    #[structopt(long, default_value = "1048576")]
+    max_value_size: usize,
+    //Largest transaction (in bytes of keys and values) a txn may carry - 1 MiB by default, as it is decided as a single entry
+    #[structopt(long, default_value = "1048576")]
+    max_transaction_size: usize,
+    //How long (in milliseconds) the leader may answer linearizable gets on its own after a majority confirmed its leadership - 0 turns leases off
+    //It has to stay below the time BLE takes to elect a new leader (a heartbeat round of 20 ticks of 20 ms), so that granting a lease rarely delays an election
+    #[structopt(long, default_value = "300")]
+    lease_ms: u64,
+    //Snapshot the key-value store after this many applied entries, or this many bytes of applied entries - 0 turns a threshold off
+    //Once every node has a snapshot, the log up to the oldest of them is trimmed
+    #[structopt(long, default_value = "10000")]
+    snapshot_entries: u64,
+    #[structopt(long, default_value = "0")]
+    snapshot_bytes: u64,
+    //Start without a configuration and wait to be added to a running cluster with add-node
+    #[structopt(long)]
+    join: bool,
 }
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct KeyValue {
     pub key: String,
-    pub value: u64,
+    pub operation: Operation,
+    //The node that proposed the entry and that node's id for the proposal, so that the write can be acknowledged once decided
+    pub origin: u64,
+    pub request_id: u64,
+}
+//The operation a log entry performs on its key
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Operation {
+    //Set the key to the value
+    Put(Vec<u8>),
+    //Tombstone - the key is removed, so later gets do not find it
+    Delete,
+    //Set the key to new, but only if its current value is expected
+    CompareAndSwap { expected: Vec<u8>, new: Vec<u8> },
+    //Set the key to the value, but only if the key does not exist
+    PutIfAbsent(Vec<u8>),
+    //Add to or subtract from the key's value, which has to be a number; the result must stay within the range of a u64
+    Increment(u64),
+    Decrement(u64),
+    //Add a value to the front or back of the list, or take one off
+    ListPush { front: bool, value: Vec<u8> },
+    ListPop { front: bool },
+    //Add a member to or remove it from the set
+    SetAdd(Vec<u8>),
+    SetRemove(Vec<u8>),
+    //Set a field of the hash to the value
+    HashSet { field: String, value: Vec<u8> },
+    //Changes nothing - a new leader proposes it so that it has decided an entry of its own ballot; the entry has no key
+    Noop,
+    //Check every guard and, only if all of them hold, carry out all of the writes - the guards and writes name their own keys, and the entry is filed under the first write key, or the first guard key if there are no writes
+    Transaction { guards: Vec<Guard>, writes: Vec<TransactionWrite> },
+}
+
+impl Operation {
+    //The name of the operation, for the node's output
+    fn kind(&self) -> &'static str {
+        match self {
+            Operation::Put(_) => "put",
+            Operation::Delete => "delete",
+            Operation::CompareAndSwap { .. } => "cas",
+            Operation::PutIfAbsent(_) => "put-if-absent",
+            Operation::Increment(_) => "incr",
+            Operation::Decrement(_) => "decr",
+            Operation::ListPush { front: true, .. } => "lpush",
+            Operation::ListPush { front: false, .. } => "rpush",
+            Operation::ListPop { front: true } => "lpop",
+            Operation::ListPop { front: false } => "rpop",
+            Operation::SetAdd(_) => "sadd",
+            Operation::SetRemove(_) => "srem",
+            Operation::HashSet { .. } => "hset",
+            Operation::Noop => "no-op",
+            Operation::Transaction { .. } => "txn",
+        }
+    }
+
+    //The number of bytes of values the operation carries - values can be large and binary, so the output only shows their length
+    fn value_bytes(&self) -> usize {
+        match self {
+            Operation::Put(value) | Operation::PutIfAbsent(value) | Operation::SetAdd(value) | Operation::SetRemove(value) => value.len(),
+            Operation::ListPush { value, .. } | Operation::HashSet { value, .. } => value.len(),
+            Operation::CompareAndSwap { expected, new } => expected.len() + new.len(),
+            Operation::Transaction { guards, writes } => {
+                let guard_bytes: usize = guards.iter().map(|guard| match &guard.condition {
+                    Condition::Equals(value) | Condition::NotEquals(value) => value.len(),
+                    Condition::Exists | Condition::Absent => 0,
+                }).sum();
+                let write_bytes: usize = writes.iter().map(|write| match write {
+                    TransactionWrite::Put(_, value) => value.len(),
+                    TransactionWrite::Delete(_) => 0,
+                }).sum();
+                guard_bytes + write_bytes
+            },
+            Operation::Delete | Operation::Increment(_) | Operation::Decrement(_) | Operation::ListPop { .. } | Operation::Noop => 0,
+        }
+    }
+}
+
+//A condition on one key that a transaction checks before it writes anything
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Guard {
+    pub key: String,
+    pub condition: Condition,
+}
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Condition {
+    //The key holds exactly this value
+    Equals(Vec<u8>),
+    //The key does not hold this value - also true if the key does not exist
+    NotEquals(Vec<u8>),
+    Exists,
+    Absent,
 }
 
+//A write that is part of a transaction
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum TransactionWrite {
+    Put(String, Vec<u8>),
+    Delete(String),
+}
+
+//A write proposed through this node that has not been decided yet - the client connection and request it came from, and when to give up
+struct PendingWrite {
+    connection: u64,
+    request_id: u64,
+    deadline: time::Instant,
+}
+
+//How a get is answered, chosen per request
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+enum ReadConsistency {
+    //From whatever this node has decided so far - fast, but a node that has fallen behind returns stale values
+    Local,
+    //After confirming the leader's decided index with a quorum and applying everything up to it
+    Linearizable,
+    //From this node if it is known to be at most the given lag behind the leader, otherwise the same as linearizable
+    Stale(MaxLag),
+}
+
+//What a get reads from the key, which has to be of the matching type
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum Query {
+    //The value of a string
+    Value,
+    //The members of a set
+    Members,
+    //The value of one field of a hash
+    Field(String),
+}
+
+//A linearizable get waiting for its read index to be confirmed (index is None until then) and applied
+struct PendingRead {
+    connection: u64,
+    request_id: u64,
+    key: String,
+    query: Query,
+    index: Option<u64>,
+    deadline: time::Instant,
+}
+
+//How long a write may take to be decided before the client is told that it failed
+const WRITE_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+//How long a linearizable read may wait for the leader before the client is told that it failed
+const READ_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+//Number of responses that can be waiting to be written to a single client connection
+const CLIENT_QUEUE_SIZE: usize = 256;
+//Number of SequencePaxos messages of the next configuration kept until the node has moved on to it
+const FUTURE_MESSAGES: usize = 1024;
+//Most guards and writes a single transaction may have
+const MAX_TRANSACTION_PARTS: usize = 128;
+
 #[tokio::main]
 async fn main() {
     //Initialize the node itself
     let node = Node::from_args();    
     let node_number = node.pid;
-    let peers = node.peers;
-    println!("Initializing node {} with peers {:?}", node_number, peers);
-
-    //The node needs to inform the client of its number of peers
-    //The client uses this to determine how many nodes there are and how to divide the key-value pairs between them
-    //Get the number
-    let number_of_peers: u64 = peers.len().try_into().unwrap();
-    //Connect to the client
-    let client_stream = TcpStream::connect(format!("127.0.0.1:64000")).await.unwrap();
-    let (client_reader, mut client_writer) = tokio::io::split(client_stream);
-    let peers_message: Vec<u8> = bincode::serialize(&number_of_peers).unwrap();
-    client_writer.write_all(&peers_message).await.unwrap();
-    println!("Informed the client of number of peers");
+    let cluster = cluster_config(&node);
+    let peers: Vec<u64> = if node.peers.is_empty() {
+        cluster.pids().into_iter().filter(|pid| *pid != node_number).collect()
+    } else {
+        node.peers.clone()
+    };
+    let own_address = cluster.node(node_number).expect("ERROR: The node is missing from the cluster configuration").clone();
+    //The configuration the node starts in - the latest one in its data directory if the cluster has been reconfigured before,
+    //an empty one if the node is waiting to join a running cluster and otherwise configuration 1 with the peers from the command line
+    let latest_membership = match &node.data_dir {
+        Some(data_dir) => Membership::load_latest(data_dir).unwrap_or_else(|error| panic!("ERROR: {}", error)),
+        None => None,
+    };
+    let membership = match latest_membership {
+        Some(membership) => membership,
+        None if node.join => Membership { config_id: 0, nodes: vec![own_address.clone()] },
+        None => {
+            let mut nodes: Vec<NodeAddress> = peers.iter().map(|peer| cluster.node(*peer).expect("ERROR: A peer is missing from the cluster configuration").clone()).collect();
+            nodes.push(own_address.clone());
+            Membership { config_id: 1, nodes }
+        },
+    };
+    let peers: Vec<u64> = membership.peers(node_number).iter().map(|peer| peer.pid).collect();
+    if membership.config_id == 0 {
+        println!("Initializing node {} - waiting to be added to the cluster", node_number);
+    } else {
+        println!("Initializing node {} in configuration {} with peers {:?}", node_number, membership.config_id, peers);
+    }
+
+    //Clients ask for the pids of the members to know which nodes there are and how to divide the key-value pairs between them
+    //They change when nodes are added or removed; a node that is not part of the cluster has none to report
+    let member_pids = Arc::new(Mutex::new(if membership.config_id > 0 { membership.pids() } else { vec![] }));
+    let member_pids_sp = member_pids.clone();
+    //The connected clients - shared between the command listener and the SequencePaxos handler
+    let clients = Clients::default();
+    let clients_sp = clients.clone();
 
     //Initialize mpsc channels
     //Channels used for BallotLeaderElection
     let (sender_outgoingble, receiver_ble) = mpsc::channel(32);
     let sender_bletimer = sender_outgoingble.clone();
     let sender_blenet = sender_outgoingble.clone();
+    //The SequencePaxos handler hands leader election the membership of a new configuration on a channel of its own
+    //It is unbounded, so the handler never waits on leader election while leader election waits on the handler
+    let (sender_reconfiguration, receiver_reconfiguration) = mpsc::unbounded_channel();
 
     //Channels used for SequencePaxos
     let (sender_blehandler, receiver_sp) = mpsc::channel(32);
     let sender_outgoingsp = sender_blehandler.clone();
     let sender_cmdlisten = sender_blehandler.clone();
     let sender_reads = sender_blehandler.clone();
+    let sender_snapshots = sender_blehandler.clone();
     
     //Configure BallotLeaderElection and SequencePaxos
-    let mut ble_config = BLEConfig::default();
-    let mut sp_config = SequencePaxosConfig::default();
+    let ble = new_ble(node_number, &membership);
+    let max_frame_size = node.max_frame_size.unwrap_or(DEFAULT_MAX_FRAME_SIZE);
+    let max_value_size = node.max_value_size;
+    let max_transaction_size = node.max_transaction_size;
+    //Values travel in hex, so a request with the largest value has to fit in a frame at twice its size
+    if max_value_size.saturating_mul(2) >= max_frame_size {
+        panic!("ERROR: The maximum value size has to be less than half the maximum frame size");
+    }
+    if max_transaction_size.saturating_mul(2) >= max_frame_size {
+        panic!("ERROR: The maximum transaction size has to be less than half the maximum frame size");
+    }
 
-    ble_config.set_pid(node_number);
-    ble_config.set_peers(peers.to_vec());
-    ble_config.set_hb_delay(20);
-    sp_config.set_configuration_id(node_number.try_into().unwrap());
-    sp_config.set_pid(node_number);
-    sp_config.set_peers(peers.to_vec());
-    
-    let ble = BallotLeaderElection::with(ble_config);
-    let storage = MemoryStorage::<KeyValue, ()>::default(); 
-    let sp = SequencePaxos::with(sp_config, storage);
+    //One long-lived connection per peer for each kind of traffic
+    let ble_connections = PeerConnections::new("BLE", membership.peers(node_number).iter().map(|peer| (peer.pid, peer.ble_address())).collect(), max_frame_size);
+    let config_id = membership.config_id;
+    let sp_node = SpNode::new(&node, membership, clients_sp, sender_reconfiguration, sender_snapshots, member_pids_sp);
+    let ble_address = own_address.ble_address();
+    let client_address = own_address.client_address();
 
     //Spawn threads 
     tokio::spawn(async move {
-        ble_network_communication(sender_blenet, &node_number).await;
+        ble_network_communication(sender_blenet, ble_address, max_frame_size).await;
     });
     tokio::spawn(async move {
         ble_periodic_timer(sender_bletimer).await;
@@ -91,52 +349,117 @@ async fn main() {
         periodic_send_messages(sender_outgoingsp, sender_outgoingble).await;
     });
     tokio::spawn(async move {
-        input_reader(sender_cmdlisten, &node_number).await;
+        input_reader(sender_cmdlisten, client_address, max_frame_size, (max_value_size, max_transaction_size), clients, member_pids).await;
     });
     tokio::spawn(async move {
-        handle_ble_messages(ble, receiver_ble, sender_blehandler).await;
+        handle_ble_messages(ble, receiver_ble, receiver_reconfiguration, sender_blehandler, ble_connections, node_number, config_id).await;
     });
     tokio::spawn(async move {
-        handle_sp_messages(sp, receiver_sp).await;
+        handle_sp_messages(sp_node, receiver_sp).await;
     });
     
     //Set up connection
-    let mut address: String = "127.0.0.1:".to_owned();
-    let node_port: u64 = 50000 + node_number;
-    address.push_str(&node_port.to_string().to_owned()); 
-    let read_listener = TcpListener::bind(address).await.unwrap();
+    let read_listener = TcpListener::bind(own_address.sp_address()).await.unwrap();
     
     //Reads have to be handled periodically
     loop {
         let (socket, _) = read_listener.accept().await.unwrap();
         let sender_x = sender_reads.clone();
         tokio::spawn(async move {
-            read_handler(socket, node_number, sender_x).await;
+            read_handler(socket, node_number, sender_x, max_frame_size).await;
         });
     }
 }
 
+//The new_ble function creates the BallotLeaderElection instance of a configuration
+fn new_ble(pid: u64, membership: &Membership) -> BallotLeaderElection {
+    let mut ble_config = BLEConfig::default();
+    ble_config.set_pid(pid);
+    ble_config.set_peers(membership.peers(pid).iter().map(|peer| peer.pid).collect());
+    ble_config.set_hb_delay(20);
+    BallotLeaderElection::with(ble_config)
+}
+
+//The new_sequence_paxos function creates the SequencePaxos instance of a configuration, with storage of its own
+//Also returns whether the storage holds state from a previous run, in which case the instance starts out recovering, and the ballot the storage has promised
+fn new_sequence_paxos(pid: u64, membership: &Membership, data_dir: &Option<PathBuf>) -> (SequencePaxos<KeyValue, (), NodeStorage>, bool, Ballot) {
+    let mut sp_config = SequencePaxosConfig::default();
+    sp_config.set_configuration_id(membership.config_id);
+    sp_config.set_pid(pid);
+    sp_config.set_peers(membership.peers(pid).iter().map(|peer| peer.pid).collect());
+    if let (Some(data_dir), true) = (data_dir, membership.config_id > 0) {
+        membership.save(data_dir);
+    }
+    let storage = NodeStorage::open(&storage_dir(membership, data_dir));
+    let recovering = storage.has_state();
+    let promised = storage.get_promise();
+    let mut sp = SequencePaxos::with(sp_config, storage);
+    //Messages may have been lost while the node was down, so it cannot just carry on from its log - the recovery phase
+    //makes it ask the leader to synchronize it before it accepts anything new
+    if recovering {
+        println!("Found state from a previous run - entering recovery");
+        sp.fail_recovery();
+    }
+    (sp, recovering, promised)
+}
+
+//The storage_dir function gives the directory a configuration keeps its log and snapshot in
+//A node waiting to join has nothing worth keeping, so it only uses memory
+fn storage_dir(membership: &Membership, data_dir: &Option<PathBuf>) -> Option<PathBuf> {
+    match data_dir {
+        Some(data_dir) if membership.config_id > 0 => Some(membership.storage_dir(data_dir)),
+        _ => None,
+    }
+}
+
+//The cluster_config function loads the cluster configuration and applies the command line overrides for this node
+fn cluster_config(node: &Node) -> ClusterConfig {
+    let mut cluster = match &node.config {
+        Some(path) => ClusterConfig::load(path).unwrap_or_else(|error| panic!("ERROR: Bad cluster configuration - {}", error)),
+        //Without a file every node is on the local host at the default ports
+        None => {
+            let mut pids = node.peers.clone();
+            pids.push(node.pid);
+            ClusterConfig::local(&pids).unwrap_or_else(|error| panic!("ERROR: {}", error))
+        },
+    };
+    //A node that is not in the file still needs an entry for its own address
+    if cluster.node(node.pid).is_none() {
+        cluster.nodes.push(NodeAddress::local(node.pid).unwrap_or_else(|error| panic!("ERROR: {} - give the node its address on the command line or in the configuration file", error)));
+    }
+    let own_address = cluster.nodes.iter_mut().find(|address| address.pid == node.pid).unwrap();
+    if let Some(host) = &node.host {
+        own_address.host = host.clone();
+    }
+    if let Some(sp_port) = node.sp_port {
+        own_address.sp_port = sp_port;
+    }
+    if let Some(ble_port) = node.ble_port {
+        own_address.ble_port = ble_port;
+    }
+    if let Some(client_port) = node.client_port {
+        own_address.client_port = client_port;
+    }
+    cluster
+}
+
 //The ble_network_communication function listens for ble network activity
-async fn ble_network_communication(sender: mpsc::Sender<(&str, Vec<u8>)>, pid: &u64) {
+async fn ble_network_communication(sender: mpsc::Sender<(&'static str, Vec<u8>)>, address: String, max_frame_size: usize) {
     println!("Listening for BLE network activity");
-    //Connect to the right address
-    let mut address: String = "127.0.0.1:".to_owned();
-    let port_for_node: u64 = 60000 + pid;
-    address.push_str(&port_for_node.to_string().to_owned()); 
-    
     let stream = TcpListener::bind(address).await.unwrap();
     
     loop {
         let (connection, _) = stream.accept().await.unwrap();    
-        let (mut connection_reader, _) = io::split(connection);
-        let mut buffer = vec![1; 128];
-        loop {
-            let n = connection_reader.read(&mut buffer).await.unwrap();
-            //Error handling - wrong message size
-            if n == 0 || n == 127 {break;}
-            //Send handling message
-            sender.send(("handle_ble", (&buffer[..n]).to_vec())).await.unwrap();
-        }
+        //Peers keep their connection open, so every connection needs its own task
+        let sender_x = sender.clone();
+        tokio::spawn(async move {
+            let (mut connection_reader, _) = io::split(connection);
+            //Each frame is one complete BLE message
+            while let Some(frame) = read_next_frame(&mut connection_reader, max_frame_size).await {
+                //Send handling message
+                sender_x.send(("handle_ble", frame)).await.unwrap();
+            }
+        });
     }
 }
 
@@ -160,94 +483,312 @@ async fn periodic_send_messages(sp_sender: mpsc::Sender<(&str, Vec<u8>)>, ble_se
 }
 
 //The read_handler function sends incoming messages to SequencePaxos handle
-async fn read_handler(socket_to_read: TcpStream, _id: u64, sender: mpsc::Sender<(&str, Vec<u8>)>) {
+async fn read_handler(socket_to_read: TcpStream, _id: u64, sender: mpsc::Sender<(&str, Vec<u8>)>, max_frame_size: usize) {
     //Establish connection
     let (mut connection, _) = io::split(socket_to_read);
-    let mut buffer = vec![1; 128];
-    //Loop through messages
-    loop {
-        let n = connection.read(&mut buffer).await.unwrap();
-        //Error handling - wrong message size
-        if n == 0 || n == 127 {break;}
-        sender.send(("handle_sp", (&buffer[..n]).to_vec())).await.unwrap();
+    //Loop through messages - each frame is one complete SequencePaxos message
+    while let Some(frame) = read_next_frame(&mut connection, max_frame_size).await {
+        sender.send(("handle_sp", frame)).await.unwrap();
     }
 }
 
-// listens for read and write commands from terminal
-async fn input_reader(sender: mpsc::Sender<(&str, Vec<u8>)>, node_id: &u64) {
-    //Connect to the right address (64500 + the id of the node)
-    let mut address: String = "127.0.0.1:".to_owned();
-    let node_port: u64 = 64500 + node_id;
-    address.push_str(&node_port.to_string().to_owned()); 
+//The read_next_frame function reads the next frame of a connection, returning None once the connection should be dropped
+async fn read_next_frame<R: io::AsyncRead + Unpin>(reader: &mut R, max_frame_size: usize) -> Option<Vec<u8>> {
+    match read_frame(reader, max_frame_size).await {
+        Ok(frame) => frame,
+        //Error handling - broken connection or a frame above the maximum size
+        Err(error) => {
+            println!("ERROR: Dropping connection - {}", error);
+            None
+        },
+    }
+}
+
+// listens for read and write commands from clients
+async fn input_reader(sender: mpsc::Sender<(&'static str, Vec<u8>)>, address: String, max_frame_size: usize, max_sizes: (usize, usize), clients: Clients, member_pids: Arc<Mutex<Vec<u64>>>) {
     let address_listener = TcpListener::bind(address).await.unwrap();
 
+    //Every connection gets an id so that responses can find their way back to it
+    let mut next_connection: u64 = 0;
     //Loop through
     loop {
         let (connection, _) = address_listener.accept().await.unwrap();
-        let (mut connection_reader, _) = io::split(connection);
-        let mut buffer = vec![1; 128];
-        loop {
-            let n = connection_reader.read(&mut buffer).await.unwrap();
-            //Error handling - wrong message size
-            if n == 0 || n == 127 {break;}
-            //Deserialize the message
-            let deserialized_message: String = bincode::deserialize(&buffer[..n]).unwrap();
-            let message_vector:Vec<&str> = deserialized_message.split(" ").collect();
-
-            match message_vector[0] {
-                "put" => {
-                    let kv = KeyValue{key: String::from(message_vector[1].to_string()), value: message_vector[2].trim().parse().expect("Error: The value should be a number")};
-                    sender.send(("put", bincode::serialize(&kv).unwrap())).await.unwrap();
-                },
-                "get" => {
-                    // send string of key to read
-                    sender.send(("get", bincode::serialize(&String::from(message_vector[1])).unwrap())).await.unwrap();
-                },
-                cmd => println!("Error: Received an unknown command"),
-    
+        next_connection += 1;
+        let sender_x = sender.clone();
+        let clients_x = clients.clone();
+        let member_pids_x = member_pids.clone();
+        //Clients keep their connection open, so every connection needs its own task
+        tokio::spawn(async move {
+            client_connection(connection, next_connection, sender_x, clients_x, max_frame_size, max_sizes, member_pids_x).await;
+        });
+    }
+}
+
+//The client_connection function reads the requests of one client and writes the responses back on the same connection
+//Requests are strings of the form "[request id] [command] [arguments]" and responses "[request id] [response]"
+//The largest value and the largest transaction a request may carry are given as a pair
+async fn client_connection(connection: TcpStream, connection_id: u64, sender: mpsc::Sender<(&'static str, Vec<u8>)>, clients: Clients, max_frame_size: usize, max_sizes: (usize, usize), member_pids: Arc<Mutex<Vec<u64>>>) {
+    let (max_value_size, max_transaction_size) = max_sizes;
+    let (mut connection_reader, mut connection_writer) = io::split(connection);
+    //Responses are queued by whoever produces them and written by a separate task, so a slow client never blocks the node
+    let (response_sender, mut response_receiver) = mpsc::channel::<String>(CLIENT_QUEUE_SIZE);
+    clients.register(connection_id, response_sender);
+    tokio::spawn(async move {
+        while let Some(response) = response_receiver.recv().await {
+            let encrypted_message: Vec<u8> = bincode::serialize(&response).unwrap();
+            if let Err(error) = write_frame(&mut connection_writer, &encrypted_message).await {
+                println!("ERROR: Could not send response to the client - {}", error);
+                break;
             }
         }
+    });
+
+    while let Some(frame) = read_next_frame(&mut connection_reader, max_frame_size).await {
+        //Deserialize the message - without it there is no request id to answer
+        let deserialized_message: String = match bincode::deserialize(&frame) {
+            Ok(deserialized_message) => deserialized_message,
+            Err(error) => {
+                println!("Error: Received a request that could not be read - {}", error);
+                continue;
+            },
+        };
+        //Keys may contain spaces, in which case they are quoted
+        let mut message_vector: Vec<String> = match split_words(&deserialized_message) {
+            Ok(message_vector) => message_vector,
+            Err(error) => {
+                //The request id never needs quotes, so the client can still be told what was wrong
+                match deserialized_message.split(' ').next().and_then(|request_id| request_id.parse::<u64>().ok()) {
+                    Some(request_id) => clients.reply(connection_id, request_id, &format!("error {}", error)),
+                    None => println!("Error: Received a request that could not be read - {}", error),
+                }
+                continue;
+            },
+        };
+        //The first word is the client's id for the request
+        let request_id: u64 = match message_vector.first().and_then(|request_id| request_id.parse().ok()) {
+            Some(request_id) => request_id,
+            None => {
+                println!("Error: Received a request without a request id");
+                continue;
+            },
+        };
+        //A client that sends a write to the node it believes is the leader marks it with "to-leader"
+        //If the node is not the leader, the client is told who is instead of the write being forwarded
+        let to_leader = message_vector.get(1).map(|word| word.trim()) == Some("to-leader");
+        if to_leader {
+            message_vector.remove(1);
+        }
+
+        //Every read and write names a key right after the command
+        let command = message_vector.get(1).map(|command| command.trim());
+        let key = match message_vector.get(2) {
+            Some(key) => key.clone(),
+            None if matches!(command, Some("put" | "delete" | "cas" | "put-if-absent" | "incr" | "decr" | "lpush" | "rpush" | "lpop" | "rpop" | "sadd" | "srem" | "hset" | "get" | "smembers" | "hget")) => {
+                clients.reply(connection_id, request_id, "error the key is missing");
+                continue;
+            },
+            None => String::new(),
+        };
+
+        match command {
+            //Values arrive in hex and may be any bytes, up to the maximum value size
+            Some("put") => {
+                match read_value(message_vector.get(3), max_value_size) {
+                    Ok(value) => sender.send(("write", bincode::serialize(&(connection_id, request_id, to_leader, key, Operation::Put(value))).unwrap())).await.unwrap(),
+                    Err(error) => clients.reply(connection_id, request_id, &format!("error {}", error)),
+                }
+            },
+            Some("delete") => {
+                sender.send(("write", bincode::serialize(&(connection_id, request_id, to_leader, key, Operation::Delete)).unwrap())).await.unwrap();
+            },
+            //Conditional writes - the condition is only evaluated once the entry is decided
+            Some("cas") => {
+                match (read_value(message_vector.get(3), max_value_size), read_value(message_vector.get(4), max_value_size)) {
+                    (Ok(expected), Ok(new)) => sender.send(("write", bincode::serialize(&(connection_id, request_id, to_leader, key, Operation::CompareAndSwap { expected, new })).unwrap())).await.unwrap(),
+                    (Err(error), _) | (_, Err(error)) => clients.reply(connection_id, request_id, &format!("error {}", error)),
+                }
+            },
+            Some("put-if-absent") => {
+                match read_value(message_vector.get(3), max_value_size) {
+                    Ok(value) => sender.send(("write", bincode::serialize(&(connection_id, request_id, to_leader, key, Operation::PutIfAbsent(value))).unwrap())).await.unwrap(),
+                    Err(error) => clients.reply(connection_id, request_id, &format!("error {}", error)),
+                }
+            },
+            //Counters - the new value is computed when the entry is applied, so concurrent increments are never lost
+            Some(command @ ("incr" | "decr")) => {
+                let delta: u64 = match message_vector.get(3).and_then(|delta| delta.parse().ok()) {
+                    Some(delta) => delta,
+                    None => {
+                        clients.reply(connection_id, request_id, "error the delta should be a number");
+                        continue;
+                    },
+                };
+                let operation = if command == "incr" { Operation::Increment(delta) } else { Operation::Decrement(delta) };
+                sender.send(("write", bincode::serialize(&(connection_id, request_id, to_leader, key, operation)).unwrap())).await.unwrap();
+            },
+            //Lists, sets and hashes - the type of the key is checked once the entry is decided
+            Some(command @ ("lpush" | "rpush")) => {
+                match read_value(message_vector.get(3), max_value_size) {
+                    Ok(value) => sender.send(("write", bincode::serialize(&(connection_id, request_id, to_leader, key, Operation::ListPush { front: command == "lpush", value })).unwrap())).await.unwrap(),
+                    Err(error) => clients.reply(connection_id, request_id, &format!("error {}", error)),
+                }
+            },
+            Some(command @ ("lpop" | "rpop")) => {
+                sender.send(("write", bincode::serialize(&(connection_id, request_id, to_leader, key, Operation::ListPop { front: command == "lpop" })).unwrap())).await.unwrap();
+            },
+            Some(command @ ("sadd" | "srem")) => {
+                match read_value(message_vector.get(3), max_value_size) {
+                    Ok(member) => {
+                        let operation = if command == "sadd" { Operation::SetAdd(member) } else { Operation::SetRemove(member) };
+                        sender.send(("write", bincode::serialize(&(connection_id, request_id, to_leader, key, operation)).unwrap())).await.unwrap();
+                    },
+                    Err(error) => clients.reply(connection_id, request_id, &format!("error {}", error)),
+                }
+            },
+            Some("hset") => {
+                let field = match message_vector.get(3) {
+                    Some(field) => field.clone(),
+                    None => {
+                        clients.reply(connection_id, request_id, "error the field is missing");
+                        continue;
+                    },
+                };
+                match read_value(message_vector.get(4), max_value_size) {
+                    Ok(value) => sender.send(("write", bincode::serialize(&(connection_id, request_id, to_leader, key, Operation::HashSet { field, value })).unwrap())).await.unwrap(),
+                    Err(error) => clients.reply(connection_id, request_id, &format!("error {}", error)),
+                }
+            },
+            //Transactions - guards and writes on any number of keys, decided as a single entry so that they are applied all at once or not at all
+            //The entry is filed under the key of its first write, or of its first guard if it only checks keys - clients route it by the same key
+            Some("txn") => match read_transaction(&message_vector[2..], max_value_size, max_transaction_size) {
+                Ok((guards, writes)) => {
+                    let key = match writes.first() {
+                        Some(TransactionWrite::Put(key, _) | TransactionWrite::Delete(key)) => key.clone(),
+                        None => guards[0].key.clone(),
+                    };
+                    sender.send(("write", bincode::serialize(&(connection_id, request_id, to_leader, key, Operation::Transaction { guards, writes })).unwrap())).await.unwrap();
+                },
+                Err(error) => clients.reply(connection_id, request_id, &format!("error {}", error)),
+            },
+            //Reads - smembers reads a set and hget a field of a hash, with the same consistencies as get
+            Some(command @ ("get" | "smembers" | "hget")) => {
+                let query = match command {
+                    "smembers" => Query::Members,
+                    "hget" => match message_vector.get(3) {
+                        Some(field) => Query::Field(field.clone()),
+                        None => {
+                            clients.reply(connection_id, request_id, "error the field is missing");
+                            continue;
+                        },
+                    },
+                    _ => Query::Value,
+                };
+                //The consistency is an optional last word - local unless asked otherwise
+                let consistency_position = if command == "hget" { 4 } else { 3 };
+                let consistency = match message_vector.get(consistency_position).map(|consistency| consistency.trim()) {
+                    None | Some("local") => ReadConsistency::Local,
+                    Some("linearizable") => ReadConsistency::Linearizable,
+                    Some(consistency) if consistency.starts_with("stale(") => match MaxLag::parse(consistency) {
+                        Some(max_lag) => ReadConsistency::Stale(max_lag),
+                        None => {
+                            clients.reply(connection_id, request_id, "error the maximum lag should be written stale(entries) or stale(milliseconds ms)");
+                            continue;
+                        },
+                    },
+                    Some(_) => {
+                        clients.reply(connection_id, request_id, "error unknown read consistency");
+                        continue;
+                    },
+                };
+                sender.send(("get", bincode::serialize(&(connection_id, request_id, key, query, consistency)).unwrap())).await.unwrap();
+            },
+            Some("status") => sender.send(("status", bincode::serialize(&(connection_id, request_id)).unwrap())).await.unwrap(),
+            Some("leader") => sender.send(("leader", bincode::serialize(&(connection_id, request_id)).unwrap())).await.unwrap(),
+            //The pids of the members can be answered right away
+            Some("nodes") => {
+                let pids: Vec<String> = member_pids.lock().unwrap().iter().map(|pid| pid.to_string()).collect();
+                clients.reply(connection_id, request_id, format!("nodes {}", pids.join(" ")).trim_end());
+            },
+            //Membership changes - answered once the new configuration has been decided
+            Some(command @ ("add-node" | "remove-node")) => {
+                let pid: u64 = match message_vector.get(2).and_then(|pid| pid.trim().parse().ok()) {
+                    Some(pid) => pid,
+                    None => {
+                        clients.reply(connection_id, request_id, "error the pid should be a number");
+                        continue;
+                    },
+                };
+                let change = if command == "add-node" {
+                    match message_vector.get(3).ok_or_else(|| "the address is missing".to_string()).and_then(|address| NodeAddress::parse(pid, address.trim())) {
+                        Ok(address) => MembershipChange::Add(address),
+                        Err(error) => {
+                            clients.reply(connection_id, request_id, &format!("error {}", error));
+                            continue;
+                        },
+                    }
+                } else {
+                    MembershipChange::Remove(pid)
+                };
+                sender.send(("reconfigure", bincode::serialize(&(connection_id, request_id, change)).unwrap())).await.unwrap();
+            },
+            _ => {
+                println!("Error: Received an unknown command");
+                clients.reply(connection_id, request_id, "error unknown command");
+            },
+        }
     }
+    //The client has disconnected
+    clients.remove(connection_id);
 }
 
 //The handle_ble_messages function handles messages related to the BallotLeaderElection functionality
-async fn handle_ble_messages(mut ble: BallotLeaderElection, mut receiver: mpsc::Receiver<(&str, Vec<u8>)>, sender: mpsc::Sender<(&str, Vec<u8>)>) {
+//Messages are tagged with the configuration they belong to, so that leftovers from an old configuration are ignored
+async fn handle_ble_messages(mut ble: BallotLeaderElection, mut receiver: mpsc::Receiver<(&str, Vec<u8>)>, mut reconfigurations: mpsc::UnboundedReceiver<Membership>, sender: mpsc::Sender<(&str, Vec<u8>)>, mut connections: PeerConnections, pid: u64, mut config_id: u32) {
+    //A node waiting to join, or one that has been removed, takes no part in leader election
+    let mut active = config_id > 0;
     //Go through received messages
     while let Some(action) = receiver.recv().await {
+        //The cluster moved on to a new configuration - start leader election over among its nodes
+        //Picked up before every message, which arrive at least every tick
+        while let Ok(membership) = reconfigurations.try_recv() {
+            ble = new_ble(pid, &membership);
+            connections.set_addresses(membership.peers(pid).iter().map(|peer| (peer.pid, peer.ble_address())).collect());
+            config_id = membership.config_id;
+            active = membership.contains(pid);
+        }
         //Match messages
         match (action.0, action.1) {
             //The leader message is a two-step message since it requires both a ble tick and a "handle leader" in SequencePaxos
             ("leader_ble", ..) => {
                 //BLE tick
-                if let Some(leader) = ble.tick() {
+                if let Some(leader) = ble.tick().filter(|_| active) {
                     //Re-serialize the message
-                    let encrypted_message: Vec<u8> = bincode::serialize(&leader).unwrap();
+                    let encrypted_message: Vec<u8> = bincode::serialize(&(config_id, leader)).unwrap();
                     //Send on to SequencePaxos
                     sender.send(("sp_leader", encrypted_message)).await.unwrap();
                 }
+                //The leader renews its lease and reports its progress at the same pace as the BLE heartbeats
+                sender.send(("leader_tick", vec![])).await.unwrap();
             },
             //BLE handle so that all messages are handled correctly
-            ("handle_ble", encrypted_message) => {
-                let deserialized_message: BLEMessage = bincode::deserialize(&encrypted_message).unwrap();
-                ble.handle(deserialized_message);
+            //A frame that cannot be read is dropped - it comes from another node and must not take this one down
+            ("handle_ble", encrypted_message) => match bincode::deserialize::<(u32, BLEMessage)>(&encrypted_message) {
+                Ok((message_config_id, deserialized_message)) => {
+                    if message_config_id == config_id {
+                        ble.handle(deserialized_message);
+                    }
+                },
+                Err(error) => println!("ERROR: Dropping a BLE message that could not be read - {}", error),
             },
             //Send the outgoing messages
             ("outgoing", ..) => {
-                //Loop through outgoing messages
-                for outgoing_message in ble.get_outgoing_msgs() {
+                //Loop through outgoing messages - a node that takes no part in leader election drops them
+                for outgoing_message in ble.get_outgoing_msgs().into_iter().filter(|_| active) {
                     //Get receiver
                     let receiver = outgoing_message.to;
-                    //Connect to the correct address
-                    match TcpStream::connect(format!("127.0.0.1:{}", 60000 + receiver)).await {
-                        Err(_) => println!("ERROR: Bad connection - retrying next round"),
-                        Ok(stream) => {
-                            //Get writer for the connection
-                            let (_reader, mut writer) = io::split(stream);
-                            //Serialize and send the messages
-                            let encrypted_message: Vec<u8> = bincode::serialize(&outgoing_message).unwrap();
-                            writer.write_all(&encrypted_message).await.unwrap();
-                        },
-                    }
+                    //Serialize the message and queue it on the connection to the receiver
+                    let encrypted_message: Vec<u8> = bincode::serialize(&(config_id, outgoing_message)).unwrap();
+                    connections.send(receiver, encrypted_message);
                 }
             },
             _ => {
@@ -258,115 +799,965 @@ async fn handle_ble_messages(mut ble: BallotLeaderElection, mut receiver: mpsc::
     }
 }
 
-//The handle_sp_messages function handles messages related to the SequencePaxos functionality
-async fn handle_sp_messages(mut sp: SequencePaxos<KeyValue, (), MemoryStorage<KeyValue, ()>>, mut receiver: mpsc::Receiver<(&str, Vec<u8>)>) {
-    //Go through received messages
-    while let Some(action) = receiver.recv().await {
-        //Match messages
-        match (action.0, action.1) {
-            //Handle leader - this message is received from the ble handling function
-            ("sp_leader", encrypted_message) => {
-                sp.handle_leader(bincode::deserialize(&encrypted_message).unwrap());
+//The SpNode struct is the node's side of SequencePaxos - the instance of the current configuration, the key-value pairs decided through it
+//and everything the node keeps track of around them: the leader it follows, writes and reads in flight, its lease, snapshots and membership changes
+struct SpNode {
+    pid: u64,
+    sp: SequencePaxos<KeyValue, (), NodeStorage>,
+    connections: PeerConnections,
+    //Nodes of earlier configurations that are not in the current one - a node that missed the stop sign is told about the configurations after it
+    former_members: PeerConnections,
+    clients: Clients,
+    membership: Membership,
+    data_dir: Option<PathBuf>,
+    //Leader election is handed the membership of each new configuration the node moves on to, and clients ask for the pids of the members
+    ble_sender: mpsc::UnboundedSender<Membership>,
+    member_pids: Arc<Mutex<Vec<u64>>>,
+    //The materialized key-value pairs - gets are answered from here instead of scanning the decided log
+    store: KeyValueStore,
+    lease: Lease,
+    snapshotter: Snapshotter,
+    //Snapshots sent to or received from other nodes, for nodes that are behind the trimmed part of the log
+    transfer: SnapshotTransfer,
+    //How far the node is behind the leader, for stale gets
+    progress: LeaderProgress,
+    read_index: ReadIndex,
+    //The highest ballot the node has promised - heartbeats for read indexes are only acknowledged for that ballot, so a deposed leader cannot confirm reads
+    promised: Ballot,
+    //Whether this node, as the leader, has decided an entry of its own ballot - only then does its log hold everything earlier leaders decided,
+    //so only then may it hand out read indexes; a new leader proposes a no-op entry for that, by its id for the proposal
+    leader_ready: bool,
+    leader_noop: Option<u64>,
+    //Prepares held back by the lease this node has granted
+    held_prepares: Vec<Message<KeyValue, ()>>,
+    //Writes proposed through this node that have not been decided yet, by the node's id for the proposal
+    pending_writes: HashMap<u64, PendingWrite>,
+    next_proposal: u64,
+    //Linearizable reads through this node, by the node's id for the read
+    pending_reads: HashMap<u64, PendingRead>,
+    next_read: u64,
+    //Reconfigurations proposed through this node, answered once the new configuration has been decided
+    pending_reconfigurations: Vec<PendingWrite>,
+    //The leader the node currently follows - when it changes, pending writes may have been lost with the old leader
+    current_leader: Option<u64>,
+    leader_ballot: Option<Ballot>,
+    //A leader elected by BLE that the node cannot switch to yet, because it has granted an unexpired lease to the current leader
+    next_leader: Option<Ballot>,
+    //A restarted node first applies what it had decided before it went down, and is then brought up to date by the leader
+    recovered_at: Option<u64>,
+    resyncing: bool,
+    //The configuration the node moves on to after handling the current message
+    next_membership: Option<Membership>,
+    //A decided stop sign whose metadata could not be read - the node stays in its configuration and reports it once
+    unreadable_stop_sign: Option<u32>,
+    //Messages of a newer configuration that arrived before this node knew about it
+    future_messages: Vec<(u32, Message<KeyValue, ()>)>,
+    //SequencePaxos batches everything proposed between two calls for its outgoing messages into one message, which has to fit in a frame
+    //The bytes proposed or received since the last call are counted, and the messages are sent early once they reach a quarter of the maximum frame size
+    max_frame_size: usize,
+    unflushed_bytes: usize,
+}
+
+impl SpNode {
+    fn new(node: &Node, membership: Membership, clients: Clients, ble_sender: mpsc::UnboundedSender<Membership>, snapshot_sender: mpsc::Sender<(&'static str, Vec<u8>)>, member_pids: Arc<Mutex<Vec<u64>>>) -> SpNode {
+        let pid = node.pid;
+        let max_frame_size = node.max_frame_size.unwrap_or(DEFAULT_MAX_FRAME_SIZE);
+        let (sp, recovering, promised) = new_sequence_paxos(pid, &membership, &node.data_dir);
+        let connections = PeerConnections::new("SP", membership.peers(pid).iter().map(|peer| (peer.pid, peer.sp_address())).collect(), max_frame_size);
+        let mut snapshotter = Snapshotter::new(storage_dir(&membership, &node.data_dir), node.snapshot_entries, node.snapshot_bytes, snapshot_sender);
+        //A node restarted with a data directory starts from its latest snapshot, since the log before it may have been trimmed
+        let store = snapshotter.load().unwrap_or_else(KeyValueStore::new);
+        let number_of_nodes = membership.nodes.len();
+        let mut transfer = SnapshotTransfer::default();
+        //A node waiting to join has nothing to apply until it has received the key-value pairs from the leader
+        if membership.config_id == 0 {
+            transfer.require();
+        }
+        let mut sp_node = SpNode {
+            pid,
+            sp,
+            connections,
+            former_members: PeerConnections::new("SP", HashMap::new(), max_frame_size),
+            clients,
+            membership,
+            data_dir: node.data_dir.clone(),
+            ble_sender,
+            member_pids,
+            store,
+            lease: Lease::new(time::Duration::from_millis(node.lease_ms), number_of_nodes),
+            snapshotter,
+            transfer,
+            progress: LeaderProgress::default(),
+            read_index: ReadIndex::new(number_of_nodes),
+            promised,
+            leader_ready: false,
+            leader_noop: None,
+            held_prepares: vec![],
+            pending_writes: HashMap::new(),
+            //Proposal ids start from the current time so that entries proposed before a restart are never mistaken for new ones
+            next_proposal: time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap().as_micros() as u64,
+            pending_reads: HashMap::new(),
+            next_read: 0,
+            pending_reconfigurations: vec![],
+            current_leader: None,
+            leader_ballot: None,
+            next_leader: None,
+            recovered_at: None,
+            resyncing: false,
+            next_membership: None,
+            unreadable_stop_sign: None,
+            future_messages: vec![],
+            max_frame_size,
+            unflushed_bytes: 0,
+        };
+        if recovering {
+            sp_node.catch_up();
+            println!("Node {} recovered at index {} - synchronizing with the leader", pid, sp_node.store.applied_index());
+            sp_node.recovered_at = Some(sp_node.store.applied_index());
+            sp_node.resyncing = true;
+        }
+        sp_node
+    }
+
+    //The catch_up function applies whatever has been decided since the last call and answers the writes and reads that were waiting for it
+    fn catch_up(&mut self) {
+        let applied = apply_decided_entries(&self.sp, &mut self.store, &mut self.snapshotter, &self.transfer);
+        if let Some(noop) = self.leader_noop {
+            if applied.iter().any(|(_, kv, _)| kv.origin == self.pid && kv.request_id == noop) {
+                self.leader_noop = None;
+                self.leader_ready = true;
+            }
+        }
+        acknowledge_writes(applied, &mut self.pending_writes, self.pid, &self.clients);
+        answer_reads(&mut self.pending_reads, &self.store, &self.clients);
+    }
+
+    //The handle_paxos function hands a SequencePaxos message to the instance, keeping track of the ballot the node promises
+    //A prepare for another ballot than the one the node has granted an unexpired lease to is held back until the lease expires,
+    //since the new leader could otherwise gather a majority and decide writes the lease holder does not know about
+    fn handle_paxos(&mut self, message: Message<KeyValue, ()>) {
+        //SequencePaxos promises every prepare with a ballot at least as high as its promise
+        if let PaxosMsg::Prepare(prepare) = &message.msg {
+            if !self.lease.allows(prepare.n) {
+                self.held_prepares.push(message);
+                return;
+            }
+            self.promised = self.promised.max(prepare.n);
+        }
+        self.sp.handle(message);
+    }
+
+    //The leader_elected function notes a leader elected by BLE; the switch itself happens after the message, once the lease allows it
+    fn leader_elected(&mut self, encrypted_message: Vec<u8>) {
+        let (leader_config_id, leader): (u32, Ballot) = bincode::deserialize(&encrypted_message).unwrap();
+        //A leader elected before the node moved on to the current configuration is ignored
+        if leader_config_id == self.membership.config_id {
+            self.next_leader = Some(leader);
+        }
+    }
+
+    //The leader_tick function runs at the pace of the BLE heartbeats
+    //The leader tells its followers how far it has decided, so that they know how stale they are, and renews its lease -
+    //granting the lease to itself as well, since it counts towards the majority
+    fn leader_tick(&mut self) {
+        let pid = self.pid;
+        if self.current_leader == Some(pid) {
+            for peer in self.connections.peers() {
+                send_to_peer(&mut self.connections, peer, &PeerMessage::LeaderProgress { from: pid, decided_idx: self.sp.get_decided_idx(), compacted_idx: self.sp.get_compacted_idx() });
+            }
+        }
+        //As with read indexes, the lease is only worth anything once the leader has decided an entry of its own ballot
+        if let (Some(ballot), true, true) = (self.leader_ballot.filter(|ballot| ballot.pid == pid), self.leader_ready, self.lease.is_enabled()) {
+            let round = self.lease.start_round(self.sp.get_decided_idx());
+            for peer in self.connections.peers() {
+                send_to_peer(&mut self.connections, peer, &PeerMessage::LeaseHeartbeat { from: pid, round, ballot });
+            }
+            self.lease.grant(ballot);
+            self.lease.acknowledge(round, pid);
+        }
+        //A node that is behind the leader's compacted index needs a snapshot before it can apply anything again, since the leader cannot send it the trimmed entries
+        let behind = self.sp.get_compacted_idx().max(self.progress.compacted_idx()) > self.store.applied_index();
+        if let Some((leader, chunk)) = self.transfer.next_request(self.current_leader.filter(|leader| *leader != pid), behind) {
+            send_to_peer(&mut self.connections, leader, &PeerMessage::SnapshotRequest { from: pid, chunk });
+        }
+        //Trim the log once every node has a snapshot covering it; SequencePaxos refuses while a node has not decided that far yet, and the next tick tries again
+        if self.current_leader == Some(pid) {
+            if let Some(index) = self.snapshotter.trim_index(&self.connections.peers()) {
+                if self.sp.trim(Some(index)).is_ok() {
+                    println!("Trimmed the log up to index {}", index);
+                    self.snapshotter.trimmed(index);
+                }
+            }
+        }
+    }
+
+    //The handle_peer_message function handles a message from another node - SequencePaxos itself and everything around it
+    fn handle_peer_message(&mut self, encrypted_message: Vec<u8>) {
+        let pid = self.pid;
+        let size = encrypted_message.len();
+        //A frame that cannot be read is dropped - it comes from another node and must not take this one down
+        let deserialized_message: PeerMessage = match bincode::deserialize(&encrypted_message) {
+            Ok(deserialized_message) => deserialized_message,
+            Err(error) => {
+                println!("ERROR: Dropping a SequencePaxos message that could not be read - {}", error);
+                return;
             },
-            //SP handle so that all messages are handled correctly
-            ("handle_sp", encrypted_message) => {
-                let deserialized_message: Message<KeyValue, ()> = bincode::deserialize(&encrypted_message).unwrap();
-                sp.handle(deserialized_message);
+        };
+        match deserialized_message {
+            PeerMessage::Paxos { config_id, message } => {
+                //Messages of a newer configuration are kept until the node gets there
+                if config_id > self.membership.config_id {
+                    if self.future_messages.len() < FUTURE_MESSAGES {
+                        self.future_messages.push((config_id, message));
+                    }
+                    return;
+                }
+                //A node that still sends messages of an older configuration has missed the stop sign, and would wait for the old configuration forever
+                if config_id < self.membership.config_id {
+                    let join = PeerMessage::Join { membership: self.membership.clone() };
+                    if self.membership.contains(message.from) {
+                        send_to_peer(&mut self.connections, message.from, &join);
+                    } else {
+                        send_to_peer(&mut self.former_members, message.from, &join);
+                    }
+                    return;
+                }
+                self.handle_paxos(message);
+                //Handling a message is what moves the decided index forward, so apply whatever became decided
+                self.catch_up();
+                //Entries forwarded to the leader go into its next batch
+                self.count_unflushed(size);
             },
-            //Send the outgoing messages - essentially the same as for BLE
-            ("outgoing", ..) => {
-                //Loop through outgoing messages
-                for outgoing_message in sp.get_outgoing_msgs() {
-                    //Connect to the correct address
-                    let receiver = outgoing_message.to;
-                    match TcpStream::connect(format!("127.0.0.1:{}", 50000 + receiver)).await {
-                        Err(_) => println!("ERROR: Bad connection - retrying next round"),
-                        Ok(stream) => {
-                            //Get writer for the connection
-                            let (_reader, mut writer) = io::split(stream);
-                            //Serialize and send the messages
-                            let encrypted_message: Vec<u8> = bincode::serialize(&outgoing_message).unwrap();
-                            writer.write_all(&encrypted_message).await.unwrap();
-                        },
+            //The node has been added to the cluster by a node of the new configuration, or it missed a stop sign and the cluster has moved on without it
+            //A member is told about the new configuration even if it is not part of it, since that is how it learns that it has been removed
+            PeerMessage::Join { membership: joined } => {
+                if joined.config_id > self.membership.config_id && (joined.contains(pid) || is_member(&self.membership, pid)) {
+                    self.next_membership = Some(joined);
+                }
+            },
+            //A follower wants a read index - only the leader can hand one out, right away if it holds the lease
+            PeerMessage::ReadIndexRequest { from, read_id } => {
+                if self.current_leader == Some(pid) {
+                    match self.lease.read_index() {
+                        Some(index) => send_to_peer(&mut self.connections, from, &PeerMessage::ReadIndexResponse { read_id, index: Some(index.max(self.sp.get_decided_idx())) }),
+                        None => self.read_index.request(ReadWaiter::Remote { from, read_id }),
                     }
+                } else {
+                    send_to_peer(&mut self.connections, from, &PeerMessage::ReadIndexResponse { read_id, index: None });
                 }
-            }
-            //Put adds a key-value pair to the key-value store through using SequencePaxos append
-            ("put", encrypted_keyvalue) => {
-                println!("Adding key-value pair into the key-value store");
-                let keyvalue_to_add: KeyValue = bincode::deserialize(&encrypted_keyvalue).unwrap();
-                sp.append(keyvalue_to_add).expect("ERROR: Could not add key-value pair into the key-value store");
-
-            },
-            //Get searches the key-value store using the decided list of SequencePaxos
-            ("get", encrypted_key) => {
-                //Get the key to search for in the key-value store
-                let key: String = bincode::deserialize(&encrypted_key).unwrap();
-                //Get all of the decided values from SequencPaxos
-                let decided_values: Option<Vec<LogEntry<KeyValue, ()>>> = sp.read_decided_suffix(0);
-                
-                //let decided_values: Option<Vec<LogEntry<KeyValue, ()>>> = sp.read_entries(0..100);
-                
-                //Connect to the client - this needs to be created twice to avoid rust complaining about "moving"
-                let client_stream = TcpStream::connect(format!("127.0.0.1:{}", 64500)).await.unwrap();
-                let (_client_reader, mut client_writer) = tokio::io::split(client_stream);
-                //Examine the decided values
-                match decided_values {
-                    Some(decided_vector) => {
-                        //For debugging purposes and testing in case something goes wrong
-                        //println!("Vector of decided values {:?}", vec.to_vec());
-                        //Get the vector itself
-                        let new_vector: Vec<LogEntry<'_, KeyValue, ()>> = decided_vector.to_vec();
-                        //Connect to the client (again)
-                        let client_stream2 = TcpStream::connect(format!("127.0.0.1:{}", 64500)).await.unwrap();
-                        let (_client_reader2, mut client_writer2) = tokio::io::split(client_stream2);  
-                        //Go through the decided vector and look for key-value pair
-                        let mut index = new_vector.len()-1;
-                        let mut response = String::new(); //Prepare response string
-                        let mut found_kv_pair = false; //Boolean to indicate whether what we are looking for has been found or not
-                        loop {
-                            match new_vector[index] {
-                                Decided(kv) => {
-                                    //If we find what we are looking for
-                                    if kv.key == key {
-                                        //Add both the key and the value to the reponse message
-                                        response.push_str(format!("{}", kv.key).as_str());
-                                        response.push_str(format!(" {}", kv.value).as_str());
-                                        //Set "found_kv_pair" to true
-                                        found_kv_pair = true;
-                                    }
-                                },
-                                _ => println!("Continuing to look for key-value pair"),
-                            };
-                            //If we have looped through the entire vector and not found anything or if we found what we wanted - we are done
-                            if index == 0 || found_kv_pair {
-                                break;
-                            }
-                            //Decrement the index we are looking at
-                            index-=1;
-                        }
-                        //If nothing was found response is still an empty string
-                        if response.len() <= 1 {
-                            //Set it to "not found"
-                            response = "not found".to_string();
+            },
+            //Only acknowledge the ballot the node has promised, otherwise a deposed leader could confirm stale reads
+            PeerMessage::ReadIndexHeartbeat { from, round, ballot } => {
+                if ballot == self.promised {
+                    send_to_peer(&mut self.connections, from, &PeerMessage::ReadIndexHeartbeatAck { from: pid, round, ballot });
+                }
+            },
+            PeerMessage::ReadIndexHeartbeatAck { from, round, ballot } => {
+                if self.leader_ballot != Some(ballot) || ballot.pid != pid {
+                    return;
+                }
+                if let Some((index, waiters)) = self.read_index.acknowledge(round, ballot, from) {
+                    confirm_reads(index, waiters, &mut self.pending_reads, &mut self.connections);
+                    answer_reads(&mut self.pending_reads, &self.store, &self.clients);
+                }
+            },
+            PeerMessage::ReadIndexResponse { read_id, index: Some(index) } => {
+                if let Some(read) = self.pending_reads.get_mut(&read_id) {
+                    read.index = Some(index);
+                }
+                answer_reads(&mut self.pending_reads, &self.store, &self.clients);
+            },
+            PeerMessage::ReadIndexResponse { read_id, index: None } => {
+                if let Some(read) = self.pending_reads.remove(&read_id) {
+                    self.clients.reply(read.connection, read.request_id, "error the leader could not confirm the read");
+                }
+            },
+            //Granting the lease means neither switching to another leader nor promising a newer ballot until it has expired
+            //Only the ballot the node has promised gets the lease, so a deposed leader cannot renew it
+            PeerMessage::LeaseHeartbeat { from, round, ballot } => {
+                if ballot == self.promised {
+                    self.lease.grant(ballot);
+                    send_to_peer(&mut self.connections, from, &PeerMessage::LeaseAck { from: pid, round, ballot });
+                }
+            },
+            PeerMessage::LeaseAck { from, round, ballot } => {
+                if self.leader_ballot == Some(ballot) && ballot.pid == pid {
+                    self.lease.acknowledge(round, from);
+                }
+            },
+            PeerMessage::SnapshotTaken { from, index } => self.snapshotter.report(from, index),
+            PeerMessage::SnapshotRequest { from, chunk } => {
+                if self.current_leader == Some(pid) {
+                    if let Some(message) = self.transfer.serve(&self.store, chunk) {
+                        send_to_peer(&mut self.connections, from, &message);
+                    }
+                }
+            },
+            PeerMessage::SnapshotChunk { index, chunk, chunks, checksum, data } => match self.transfer.receive(index, chunk, chunks, checksum, data) {
+                Received::Request(next_chunk) => {
+                    if let Some(leader) = self.current_leader {
+                        send_to_peer(&mut self.connections, leader, &PeerMessage::SnapshotRequest { from: pid, chunk: next_chunk });
+                    }
+                },
+                //Install the snapshot, keep it as this node's own snapshot and continue with the log after it
+                Received::Complete(snapshot) => {
+                    if self.transfer.is_required() || snapshot.applied_index() > self.store.applied_index() {
+                        self.transfer.installed();
+                        println!("Installed a snapshot from the leader up to index {}", snapshot.applied_index());
+                        self.store = snapshot;
+                        if let Some(index) = self.snapshotter.take(&self.store) {
+                            self.report_snapshot(index);
                         }
-                        //Send the message to the client (key-value pair in case something was found, "not found" otherwise)
-                        let encrypted_message: Vec<u8> = bincode::serialize(&response).unwrap();
-                        client_writer2.write_all(&encrypted_message).await.unwrap();
+                        self.catch_up();
+                    }
+                },
+                //Started over on the next tick
+                Received::Restart | Received::Ignore => {},
+            },
+            PeerMessage::LeaderProgress { from, decided_idx, compacted_idx } => {
+                if self.current_leader == Some(from) {
+                    self.progress.report(decided_idx, compacted_idx);
+                    if self.resyncing && self.store.applied_index() >= decided_idx {
+                        println!("Node {} is in sync with leader {} at index {}", pid, from, self.store.applied_index());
+                        self.resyncing = false;
+                    }
+                }
+            },
+        }
+    }
+
+    //The send_outgoing function sends the outgoing messages - essentially the same as for BLE - and gives up on requests that took too long
+    fn send_outgoing(&mut self) {
+        let pid = self.pid;
+        //Hand over the prepares whose lease has expired in the meantime
+        for message in std::mem::take(&mut self.held_prepares) {
+            self.handle_paxos(message);
+        }
+        //Confirm the reads that have come in since the last round - the leader acknowledges its own round right away
+        //Until it has decided an entry of its own ballot its decided index may miss entries an earlier leader decided, so the reads keep waiting
+        if let (Some(ballot), true) = (self.leader_ballot, self.leader_ready) {
+            if let Some(round) = self.read_index.start_round(self.sp.get_decided_idx(), ballot) {
+                for peer in self.connections.peers() {
+                    send_to_peer(&mut self.connections, peer, &PeerMessage::ReadIndexHeartbeat { from: pid, round, ballot });
+                }
+                if let Some((index, waiters)) = self.read_index.acknowledge(round, ballot, pid) {
+                    confirm_reads(index, waiters, &mut self.pending_reads, &mut self.connections);
+                    answer_reads(&mut self.pending_reads, &self.store, &self.clients);
+                }
+            }
+        }
+        self.send_paxos_messages();
+        //Give up on writes that have not been decided in time
+        let now = time::Instant::now();
+        let expired: Vec<u64> = self.pending_writes.iter().filter(|(_, write)| write.deadline <= now).map(|(proposal, _)| *proposal).collect();
+        for proposal in expired {
+            let write = self.pending_writes.remove(&proposal).unwrap();
+            self.clients.reply(write.connection, write.request_id, "error timed out before the write was decided");
+        }
+        for reconfiguration in self.pending_reconfigurations.iter().filter(|reconfiguration| reconfiguration.deadline <= now) {
+            self.clients.reply(reconfiguration.connection, reconfiguration.request_id, "error timed out before the reconfiguration was decided");
+        }
+        self.pending_reconfigurations.retain(|reconfiguration| reconfiguration.deadline > now);
+        //The same for reads - both those waiting at this node and those the leader could not confirm
+        for waiter in self.read_index.expire(READ_TIMEOUT) {
+            reject_read(waiter, &mut self.pending_reads, &mut self.connections, &self.clients);
+        }
+        let expired: Vec<u64> = self.pending_reads.iter().filter(|(_, read)| read.deadline <= now).map(|(read_id, _)| *read_id).collect();
+        for read_id in expired {
+            let read = self.pending_reads.remove(&read_id).unwrap();
+            self.clients.reply(read.connection, read.request_id, "error timed out before the read was confirmed");
+        }
+    }
+
+    //The send_paxos_messages function sends the messages SequencePaxos has batched up
+    fn send_paxos_messages(&mut self) {
+        self.unflushed_bytes = 0;
+        //Loop through outgoing messages
+        for outgoing_message in self.sp.get_outgoing_msgs() {
+            //Wrap the message and queue it on the connection to the receiver
+            let receiver = outgoing_message.to;
+            send_to_peer(&mut self.connections, receiver, &PeerMessage::Paxos { config_id: self.membership.config_id, message: outgoing_message });
+        }
+    }
+
+    //The count_unflushed function counts bytes that may end up in the next batch, and sends the batch right away once it gets too large for a frame
+    fn count_unflushed(&mut self, bytes: usize) {
+        self.unflushed_bytes += bytes;
+        if self.unflushed_bytes >= self.max_frame_size / 4 {
+            self.send_paxos_messages();
+        }
+    }
+
+    //The write function adds an entry for the key to the log through using SequencePaxos append
+    fn write(&mut self, encrypted_request: Vec<u8>) {
+        let pid = self.pid;
+        let (connection, request_id, to_leader, key, operation): (u64, u64, bool, String, Operation) = bincode::deserialize(&encrypted_request).unwrap();
+        if !is_member(&self.membership, pid) {
+            self.clients.reply(connection, request_id, "error the node is not part of the cluster");
+            return;
+        }
+        //The client's idea of the leader is out of date - tell it who the leader is (if anyone) and its ballot, so that it can resend the write there
+        if to_leader && self.current_leader != Some(pid) {
+            let response = match self.leader_ballot {
+                Some(ballot) => format!("redirect {} {}", ballot.pid, ballot.n),
+                None => "redirect none".to_string(),
+            };
+            self.clients.reply(connection, request_id, &response);
+            return;
+        }
+        println!("Adding {} of key {} ({} bytes of values) into the key-value store", operation.kind(), quote(&key), operation.value_bytes());
+        self.next_proposal += 1;
+        let keyvalue_to_add = KeyValue{key, operation, origin: pid, request_id: self.next_proposal};
+        let size = bincode::serialized_size(&keyvalue_to_add).unwrap() as usize;
+        match self.sp.append(keyvalue_to_add) {
+            Ok(_) => {
+                self.pending_writes.insert(self.next_proposal, PendingWrite{connection, request_id, deadline: time::Instant::now() + WRITE_TIMEOUT});
+                //A single-node cluster decides immediately on append
+                self.catch_up();
+                self.count_unflushed(size);
+            },
+            Err(_) => {
+                println!("ERROR: Could not add the entry into the key-value store");
+                self.clients.reply(connection, request_id, "error the write could not be proposed");
+            },
+        }
+    }
+
+    //The get function looks the key up in the materialized key-value pairs
+    fn get(&mut self, encrypted_request: Vec<u8>) {
+        let pid = self.pid;
+        //Get the key to search for in the key-value store
+        let (connection, request_id, key, query, consistency): (u64, u64, String, Query, ReadConsistency) = bincode::deserialize(&encrypted_request).unwrap();
+        //Make sure everything decided so far is reflected in the store
+        self.catch_up();
+        let up_to_date = match consistency {
+            ReadConsistency::Stale(max_lag) => self.current_leader == Some(pid) || self.progress.is_within(max_lag, self.store.applied_index()),
+            _ => false,
+        };
+        match consistency {
+            ReadConsistency::Local => self.clients.reply(connection, request_id, &read_response(&self.store, &key, &query)),
+            ReadConsistency::Stale(_) if up_to_date => self.clients.reply(connection, request_id, &read_response(&self.store, &key, &query)),
+            //The read is answered once the leader has confirmed its read index and this node has applied up to it
+            //A stale get from a node that is too far behind (or does not know how far behind it is) is forwarded to the leader the same way
+            ReadConsistency::Linearizable | ReadConsistency::Stale(_) => {
+                self.next_read += 1;
+                let read_id = self.next_read;
+                self.pending_reads.insert(read_id, PendingRead{connection, request_id, key, query, index: None, deadline: time::Instant::now() + READ_TIMEOUT});
+                match self.current_leader {
+                    //With a valid lease the leader answers as soon as it has applied up to the lease's index
+                    Some(leader) if leader == pid => match self.lease.read_index() {
+                        Some(index) => {
+                            self.pending_reads.get_mut(&read_id).unwrap().index = Some(index.max(self.sp.get_decided_idx()));
+                            answer_reads(&mut self.pending_reads, &self.store, &self.clients);
+                        },
+                        None => self.read_index.request(ReadWaiter::Local(read_id)),
                     },
-                    _ => {
-                        //If we do not get a vector that means that nothing is in the key-value store, so we need to throw an error
-                        println!("ERROR: Could not get KeyValue pair from Sequence Paxos");
-                        //This needs to be sent to the client so that it does not get an error and informs the user that the key was not found
-                        let notfound_message: Vec<u8> = bincode::serialize("not found").unwrap();
-                        client_writer.write_all(&notfound_message).await.unwrap();
+                    Some(leader) => send_to_peer(&mut self.connections, leader, &PeerMessage::ReadIndexRequest { from: pid, read_id }),
+                    None => {
+                        self.pending_reads.remove(&read_id);
+                        self.clients.reply(connection, request_id, "error there is no leader to confirm the read with");
                     },
                 }
             },
+        }
+    }
+
+    //The status function gives the node's view of the cluster and how far it has got, for the operator
+    fn status(&self, encrypted_request: Vec<u8>) {
+        let (connection, request_id): (u64, u64) = bincode::deserialize(&encrypted_request).unwrap();
+        let leader = match self.current_leader {
+            Some(leader) => leader.to_string(),
+            None => "none".to_string(),
+        };
+        let recovered = match self.recovered_at {
+            Some(index) => index.to_string(),
+            None => "fresh".to_string(),
+        };
+        let sync = if self.resyncing { "syncing" } else { "in-sync" };
+        self.clients.reply(connection, request_id, &format!("status {} {} {} {} {} {} {}", self.pid, self.membership.config_id, leader, self.store.applied_index(), self.sp.get_decided_idx(), recovered, sync));
+    }
+
+    //The leader function tells the leader this node follows and the ballot it was elected with, so that clients can send their writes straight to it
+    fn leader(&self, encrypted_request: Vec<u8>) {
+        let (connection, request_id): (u64, u64) = bincode::deserialize(&encrypted_request).unwrap();
+        let response = match self.leader_ballot {
+            Some(ballot) => format!("leader {} {}", ballot.pid, ballot.n),
+            None => "leader none".to_string(),
+        };
+        self.clients.reply(connection, request_id, &response);
+    }
+
+    //The reconfigure function proposes a stop sign that ends the current configuration and carries the membership of the next one, for add-node and remove-node
+    fn reconfigure(&mut self, encrypted_request: Vec<u8>) {
+        let (connection, request_id, change): (u64, u64, MembershipChange) = bincode::deserialize(&encrypted_request).unwrap();
+        if !is_member(&self.membership, self.pid) {
+            self.clients.reply(connection, request_id, "error the node is not part of the cluster");
+            return;
+        }
+        match self.membership.change(change) {
+            Ok(new_membership) => {
+                println!("Proposing configuration {} with nodes {:?}", new_membership.config_id, new_membership.pids());
+                let request = ReconfigurationRequest::with(new_membership.pids(), Some(bincode::serialize(&new_membership).unwrap()));
+                match self.sp.reconfigure(request) {
+                    Ok(_) => self.pending_reconfigurations.push(PendingWrite{connection, request_id, deadline: time::Instant::now() + WRITE_TIMEOUT}),
+                    Err(_) => {
+                        println!("ERROR: Could not propose the reconfiguration");
+                        self.clients.reply(connection, request_id, "error the reconfiguration could not be proposed");
+                    },
+                }
+            },
+            Err(error) => self.clients.reply(connection, request_id, &format!("error {}", error)),
+        }
+    }
+
+    //The next_configuration function gives the configuration the node has to move on to, if any
+    //A decided stop sign ends the current configuration; its metadata is the membership of the next one
+    fn next_configuration(&mut self) -> Option<Membership> {
+        if let Some(stop_sign) = self.sp.is_reconfigured() {
+            if stop_sign.config_id > self.membership.config_id && self.unreadable_stop_sign != Some(stop_sign.config_id) {
+                if let Some(metadata) = &stop_sign.metadata {
+                    match bincode::deserialize(metadata) {
+                        Ok(new_membership) => self.next_membership = Some(new_membership),
+                        Err(error) => {
+                            println!("ERROR: The stop sign for configuration {} does not describe a configuration, staying in configuration {}: {}", stop_sign.config_id, self.membership.config_id, error);
+                            self.unreadable_stop_sign = Some(stop_sign.config_id);
+                        },
+                    }
+                }
+            }
+        }
+        self.next_membership.take()
+    }
+
+    //The leave_configuration function finishes what can be finished in the current configuration before the node moves on to the next one
+    fn leave_configuration(&mut self, new_membership: &Membership) {
+        println!("Moving from configuration {} to configuration {} with nodes {:?}", self.membership.config_id, new_membership.config_id, new_membership.pids());
+        //Everything decided before the stop sign still counts
+        self.catch_up();
+        for reconfiguration in self.pending_reconfigurations.drain(..) {
+            self.clients.reply(reconfiguration.connection, reconfiguration.request_id, &format!("reconfigured {}", new_membership.config_id));
+        }
+        //Writes and reads that were not done in the old configuration are not carried over to the new one
+        for (_, write) in self.pending_writes.drain() {
+            self.clients.reply(write.connection, write.request_id, "error the cluster was reconfigured before the write was decided");
+        }
+        for waiter in self.read_index.abandon() {
+            reject_read(waiter, &mut self.pending_reads, &mut self.connections, &self.clients);
+        }
+        for (_, read) in self.pending_reads.drain() {
+            self.clients.reply(read.connection, read.request_id, "error the cluster was reconfigured before the read was answered");
+        }
+        let number_of_nodes = new_membership.nodes.len();
+        self.lease.resize(number_of_nodes);
+        self.progress.reset();
+        self.read_index = ReadIndex::new(number_of_nodes);
+        self.current_leader = None;
+        self.leader_ballot = None;
+        self.leader_ready = false;
+        self.leader_noop = None;
+        self.held_prepares.clear();
+        self.next_leader = None;
+        self.ble_sender.send(new_membership.clone()).expect("ERROR: Leader election has stopped");
+    }
+
+    //The enter_configuration function moves the node on to a new configuration it is part of, with a new SequencePaxos instance, keeping the key-value pairs
+    fn enter_configuration(&mut self, new_membership: Membership) {
+        let pid = self.pid;
+        //A node that joined or was told about the configuration by another node has not applied everything decided before it
+        let missed_stop_sign = !matches!(self.sp.is_reconfigured(), Some(stop_sign) if stop_sign.config_id == new_membership.config_id);
+        //The new configuration starts with an empty log, so the key-value pairs carried over are snapshotted in its storage directory right away
+        let (sp, _, promised) = new_sequence_paxos(pid, &new_membership, &self.data_dir);
+        self.sp = sp;
+        self.promised = promised;
+        self.store.start_configuration();
+        self.snapshotter = self.snapshotter.restart(storage_dir(&new_membership, &self.data_dir));
+        self.snapshotter.take(&self.store);
+        self.connections.set_addresses(new_membership.peers(pid).iter().map(|peer| (peer.pid, peer.sp_address())).collect());
+        *self.member_pids.lock().unwrap() = new_membership.pids();
+        self.former_members.add_addresses(self.membership.peers(pid).iter().filter(|node| !new_membership.contains(node.pid)).map(|node| (node.pid, node.sp_address())).collect());
+        //Nodes that are new to the cluster do not see the stop sign, so they are told about the configuration directly
+        for node in new_membership.nodes.iter().filter(|node| !self.membership.contains(node.pid)) {
+            send_to_peer(&mut self.connections, node.pid, &PeerMessage::Join { membership: new_membership.clone() });
+        }
+        self.membership = new_membership;
+        for (config_id, message) in std::mem::take(&mut self.future_messages) {
+            if config_id == self.membership.config_id {
+                self.handle_paxos(message);
+            } else if config_id > self.membership.config_id {
+                self.future_messages.push((config_id, message));
+            }
+        }
+        //Such a node gets the key-value pairs from the leader of the new configuration
+        self.transfer = SnapshotTransfer::default();
+        if missed_stop_sign {
+            self.transfer.require();
+        }
+    }
+
+    //The snapshot_if_due function snapshots the store once enough has been applied since the last snapshot
+    //The leader is told about it once it is durable - right away without a data directory
+    fn snapshot_if_due(&mut self) {
+        if self.snapshotter.is_due() {
+            if let Some(index) = self.snapshotter.take(&self.store) {
+                self.report_snapshot(index);
+            }
+        }
+    }
+
+    //The snapshot_saved function is told when a snapshot has been written to the data directory, and lets the leader know
+    fn snapshot_saved(&mut self, encrypted_message: Vec<u8>) {
+        let (dir, index): (PathBuf, u64) = bincode::deserialize(&encrypted_message).unwrap();
+        if let Some(index) = self.snapshotter.saved(&dir, index) {
+            self.report_snapshot(index);
+        }
+    }
+
+    //The report_snapshot function tells the leader how far this node's snapshot reaches, so that it knows how far the log can be trimmed
+    fn report_snapshot(&mut self, index: u64) {
+        match self.current_leader {
+            Some(leader) if leader != self.pid => send_to_peer(&mut self.connections, leader, &PeerMessage::SnapshotTaken { from: self.pid, index }),
+            _ => {},
+        }
+    }
+
+    //The switch_leader function switches to a new leader once no lease granted to another leader holds the node back
+    fn switch_leader(&mut self) {
+        let pid = self.pid;
+        let leader = match self.next_leader {
+            Some(leader) if self.lease.allows(leader) => leader,
+            _ => return,
+        };
+        self.next_leader = None;
+        self.sp.handle_leader(leader);
+        //Writes forwarded to a leader that is gone might never be decided, so the clients are told not to count on them
+        if self.current_leader.is_some() && self.current_leader != Some(leader.pid) {
+            for (_, write) in self.pending_writes.drain() {
+                self.clients.reply(write.connection, write.request_id, "error leader changed before the write was decided");
+            }
+            //The same goes for reads the old leader has not confirmed yet; reads that already have their index can still be answered
+            for waiter in self.read_index.abandon() {
+                reject_read(waiter, &mut self.pending_reads, &mut self.connections, &self.clients);
+            }
+            let unconfirmed: Vec<u64> = self.pending_reads.iter().filter(|(_, read)| read.index.is_none()).map(|(read_id, _)| *read_id).collect();
+            for read_id in unconfirmed {
+                let read = self.pending_reads.remove(&read_id).unwrap();
+                self.clients.reply(read.connection, read.request_id, "error leader changed before the read was confirmed");
+            }
+            self.lease.revoke();
+            self.progress.reset();
+        }
+        //A recovering node that is elected leader has synchronized the others with itself, so it is as up to date as the cluster
+        if self.resyncing && leader.pid == pid {
+            println!("Node {} is in sync as the leader at index {}", pid, self.store.applied_index());
+            self.resyncing = false;
+        }
+        //The new leader needs to know how far this node has snapshotted before it can trim the log
+        if self.current_leader != Some(leader.pid) && leader.pid != pid {
+            send_to_peer(&mut self.connections, leader.pid, &PeerMessage::SnapshotTaken { from: pid, index: self.snapshotter.index() });
+        }
+        self.current_leader = Some(leader.pid);
+        //A leader elected with a new ballot starts out unable to hand out read indexes, until its no-op entry is decided
+        if self.leader_ballot != Some(leader) {
+            self.leader_ready = false;
+            self.leader_noop = None;
+            self.lease.revoke();
+            if leader.pid == pid {
+                self.promised = self.promised.max(leader);
+                self.propose_noop();
+            }
+        }
+        self.leader_ballot = Some(leader);
+        self.catch_up();
+    }
+
+    //The propose_noop function proposes the no-op entry a new leader needs to have decided before it hands out read indexes
+    fn propose_noop(&mut self) {
+        self.next_proposal += 1;
+        let noop = KeyValue{key: String::new(), operation: Operation::Noop, origin: self.pid, request_id: self.next_proposal};
+        match self.sp.append(noop) {
+            Ok(_) => self.leader_noop = Some(self.next_proposal),
+            Err(_) => println!("ERROR: Could not propose the no-op entry of the new leader"),
+        }
+    }
+}
+
+//The handle_sp_messages function handles messages related to the SequencePaxos functionality
+//When a reconfiguration is decided it moves on to a new SequencePaxos instance for the new configuration, keeping the key-value pairs
+async fn handle_sp_messages(mut node: SpNode, mut receiver: mpsc::Receiver<(&str, Vec<u8>)>) {
+    //Go through received messages
+    while let Some(action) = receiver.recv().await {
+        //Match messages
+        match (action.0, action.1) {
+            //Handle leader - this message is received from the ble handling function
+            ("sp_leader", encrypted_message) => node.leader_elected(encrypted_message),
+            ("leader_tick", ..) => node.leader_tick(),
+            //SP handle so that all messages are handled correctly
+            ("handle_sp", encrypted_message) => node.handle_peer_message(encrypted_message),
+            ("outgoing", ..) => node.send_outgoing(),
+            //Write adds an entry for the key to the log, get looks the key up
+            ("write", encrypted_request) => node.write(encrypted_request),
+            ("get", encrypted_request) => node.get(encrypted_request),
+            ("status", encrypted_request) => node.status(encrypted_request),
+            ("leader", encrypted_request) => node.leader(encrypted_request),
+            ("reconfigure", encrypted_request) => node.reconfigure(encrypted_request),
+            //A snapshot has been written to the data directory in the background
+            ("snapshot_saved", encrypted_message) => node.snapshot_saved(encrypted_message),
             _ => {
                 //If we get an unsupported message
                 println!("Received an unknown message type!");
             }
         }
-    }                
-}
\ No newline at end of file
+        //Move on to the next configuration - or stop taking part in the cluster if the node is not in it
+        match node.next_configuration() {
+            Some(new_membership) if !new_membership.contains(node.pid) => {
+                node.leave_configuration(&new_membership);
+                println!("Node {} has been removed from the cluster", node.pid);
+                node.membership = new_membership;
+                break;
+            },
+            Some(new_membership) => {
+                node.leave_configuration(&new_membership);
+                node.enter_configuration(new_membership);
+            },
+            None => {
+                node.snapshot_if_due();
+                node.switch_leader();
+            },
+        }
+    }
+    serve_removed(node, receiver).await;
+}
+
+//The serve_removed function keeps answering the clients of a node that has been removed from the cluster
+//Its SequencePaxos instance is dropped, so writes and reconfigurations fail; local gets are still answered from the key-value pairs it had when it was removed
+//Nodes of the old configuration that still send it messages have missed the stop sign as well, and are told about the new configuration
+async fn serve_removed(node: SpNode, mut receiver: mpsc::Receiver<(&str, Vec<u8>)>) {
+    let SpNode { pid, mut connections, mut former_members, clients, membership, member_pids, store, recovered_at, .. } = node;
+    member_pids.lock().unwrap().clear();
+    while let Some(action) = receiver.recv().await {
+        match (action.0, action.1) {
+            ("handle_sp", encrypted_message) => {
+                if let Ok(PeerMessage::Paxos { config_id, message }) = bincode::deserialize(&encrypted_message) {
+                    if config_id < membership.config_id {
+                        let join = PeerMessage::Join { membership: membership.clone() };
+                        if connections.peers().contains(&message.from) {
+                            send_to_peer(&mut connections, message.from, &join);
+                        } else {
+                            send_to_peer(&mut former_members, message.from, &join);
+                        }
+                    }
+                }
+            },
+            ("write", encrypted_request) => {
+                let (connection, request_id, ..): (u64, u64, bool, String, Operation) = bincode::deserialize(&encrypted_request).unwrap();
+                clients.reply(connection, request_id, "error the node is not part of the cluster");
+            },
+            ("reconfigure", encrypted_request) => {
+                let (connection, request_id, _): (u64, u64, MembershipChange) = bincode::deserialize(&encrypted_request).unwrap();
+                clients.reply(connection, request_id, "error the node is not part of the cluster");
+            },
+            ("get", encrypted_request) => {
+                let (connection, request_id, key, query, consistency): (u64, u64, String, Query, ReadConsistency) = bincode::deserialize(&encrypted_request).unwrap();
+                match consistency {
+                    ReadConsistency::Local => clients.reply(connection, request_id, &read_response(&store, &key, &query)),
+                    _ => clients.reply(connection, request_id, "error there is no leader to confirm the read with"),
+                }
+            },
+            ("status", encrypted_request) => {
+                let (connection, request_id): (u64, u64) = bincode::deserialize(&encrypted_request).unwrap();
+                let recovered = match recovered_at {
+                    Some(index) => index.to_string(),
+                    None => "fresh".to_string(),
+                };
+                clients.reply(connection, request_id, &format!("status {} {} none {} {} {} in-sync", pid, membership.config_id, store.applied_index(), store.applied_index(), recovered));
+            },
+            ("leader", encrypted_request) => {
+                let (connection, request_id): (u64, u64) = bincode::deserialize(&encrypted_request).unwrap();
+                clients.reply(connection, request_id, "leader none");
+            },
+            //Everything else belongs to the configuration the node has left
+            _ => {},
+        }
+    }
+}
+
+//The is_member function tells whether the node takes part in its configuration - not if it is waiting to join or has been removed
+fn is_member(membership: &Membership, pid: u64) -> bool {
+    membership.config_id > 0 && membership.contains(pid)
+}
+
+//The apply_decided_entries function applies the entries that have been decided since the last call to the key-value store
+//Returns the applied entries together with their log index and the result of applying them
+fn apply_decided_entries(sp: &SequencePaxos<KeyValue, (), NodeStorage>, store: &mut KeyValueStore, snapshotter: &mut Snapshotter, transfer: &SnapshotTransfer) -> Vec<(u64, KeyValue, WriteResult)> {
+    let mut applied = vec![];
+    //Entries before the compacted index are gone from the log - the node has to install a snapshot first
+    //The same goes for a node that joined the cluster, which does not have the key-value pairs from before it joined
+    if transfer.is_required() || sp.get_compacted_idx() > store.applied_index() {
+        return applied;
+    }
+    //Only the suffix after the applied index is read, so the cost is proportional to the number of new entries
+    if let Some(decided_entries) = sp.read_decided_suffix(store.applied_index()) {
+        for entry in decided_entries {
+            match entry {
+                Decided(kv) => {
+                    let index = store.applied_index();
+                    let result = store.apply(kv);
+                    snapshotter.record(kv);
+                    applied.push((index, kv.clone(), result));
+                },
+                _ => store.skip(),
+            }
+        }
+    }
+    applied
+}
+
+//The acknowledge_writes function tells the clients about every write proposed through this node that has now been decided
+//A conditional write whose condition failed is reported with the key's actual value ("absent" if it does not exist)
+fn acknowledge_writes(applied: Vec<(u64, KeyValue, WriteResult)>, pending_writes: &mut HashMap<u64, PendingWrite>, pid: u64, clients: &Clients) {
+    for (index, kv, result) in applied {
+        if kv.origin != pid {
+            continue;
+        }
+        if let Some(write) = pending_writes.remove(&kv.request_id) {
+            let response = match result {
+                WriteResult::Applied => format!("ack {}", index),
+                WriteResult::ConditionFailed(Some(current)) => format!("condition-failed {} {}", index, quote(&to_hex(&current))),
+                WriteResult::ConditionFailed(None) => format!("condition-failed {} absent", index),
+                WriteResult::Counter(value) => format!("counter {} {}", index, value),
+                WriteResult::OutOfRange(Some(current)) => format!("out-of-range {} {}", index, current),
+                WriteResult::OutOfRange(None) => format!("out-of-range {} absent", index),
+                WriteResult::NotANumber => format!("not-a-number {}", index),
+                WriteResult::Length(length) => format!("length {} {}", index, length),
+                WriteResult::Popped(Some(value)) => format!("popped {} {}", index, quote(&to_hex(&value))),
+                WriteResult::Popped(None) => format!("popped {} absent", index),
+                WriteResult::WrongType(kind) => format!("wrong-type {} {}", index, kind),
+                WriteResult::Transaction(true) => format!("txn {} passed", index),
+                WriteResult::Transaction(false) => format!("txn {} failed", index),
+            };
+            clients.reply(write.connection, write.request_id, &response);
+        }
+    }
+}
+
+//The send_to_peer function serializes a message and queues it on the connection to a peer
+fn send_to_peer(connections: &mut PeerConnections, to: u64, message: &PeerMessage) {
+    let encrypted_message: Vec<u8> = bincode::serialize(message).unwrap();
+    connections.send(to, encrypted_message);
+}
+
+//The read_value function reads a value of a request, which is written in hex
+fn read_value(word: Option<&String>, max_value_size: usize) -> Result<Vec<u8>, String> {
+    let value = from_hex(word.ok_or("the value is missing")?).map_err(|error| format!("the value should be written in hex - {}", error))?;
+    if value.len() > max_value_size {
+        return Err(format!("the value is {} bytes, more than the maximum of {} bytes", value.len(), max_value_size));
+    }
+    Ok(value)
+}
+
+//The read_transaction function reads the guards and writes of a transaction, in any order:
+//"if [key] == [value]", "if [key] != [value]", "if [key] exists", "if [key] absent", "put [key] [value]" and "delete [key]", with values in hex
+//A transaction is decided as a single entry, so the number of its parts and the total size of its keys and values are limited
+fn read_transaction(words: &[String], max_value_size: usize, max_transaction_size: usize) -> Result<(Vec<Guard>, Vec<TransactionWrite>), String> {
+    let mut guards = vec![];
+    let mut writes = vec![];
+    let mut words = words.iter();
+    while let Some(word) = words.next() {
+        if guards.len() + writes.len() == MAX_TRANSACTION_PARTS {
+            return Err(format!("the transaction has more than the maximum of {} guards and writes", MAX_TRANSACTION_PARTS));
+        }
+        match word.as_str() {
+            "if" => {
+                let key = words.next().ok_or("a guard is missing its key")?.clone();
+                let condition = match words.next().map(|comparison| comparison.as_str()) {
+                    Some("==") => Condition::Equals(read_value(words.next(), max_value_size)?),
+                    Some("!=") => Condition::NotEquals(read_value(words.next(), max_value_size)?),
+                    Some("exists") => Condition::Exists,
+                    Some("absent") => Condition::Absent,
+                    _ => return Err("a guard should compare with ==, != or be exists or absent".to_string()),
+                };
+                guards.push(Guard { key, condition });
+            },
+            "put" => {
+                let key = words.next().ok_or("a put is missing its key")?.clone();
+                writes.push(TransactionWrite::Put(key, read_value(words.next(), max_value_size)?));
+            },
+            "delete" => writes.push(TransactionWrite::Delete(words.next().ok_or("a delete is missing its key")?.clone())),
+            other => return Err(format!("unknown transaction part {}", quote(other))),
+        }
+    }
+    if guards.is_empty() && writes.is_empty() {
+        return Err("the transaction is empty".to_string());
+    }
+    let guard_bytes: usize = guards.iter().map(|guard| guard.key.len() + match &guard.condition {
+        Condition::Equals(value) | Condition::NotEquals(value) => value.len(),
+        Condition::Exists | Condition::Absent => 0,
+    }).sum();
+    let write_bytes: usize = writes.iter().map(|write| match write {
+        TransactionWrite::Put(key, value) => key.len() + value.len(),
+        TransactionWrite::Delete(key) => key.len(),
+    }).sum();
+    let size = guard_bytes + write_bytes;
+    if size > max_transaction_size {
+        return Err(format!("the transaction is {} bytes, more than the maximum of {} bytes", size, max_transaction_size));
+    }
+    Ok((guards, writes))
+}
+
+//The read_response function prepares the response to a get - the key-value pair in case something was found, "not-found" otherwise
+//The members of a set are listed in order; a key of another type than the query is for is reported with its type
+fn read_response(store: &KeyValueStore, key: &str, query: &Query) -> String {
+    match (store.get(key), query) {
+        (Some(Value::String(value)), Query::Value) => format!("value {} {}", quote(key), quote(&to_hex(value))),
+        (Some(Value::Set(set)), Query::Members) => {
+            let members: Vec<String> = set.iter().map(|member| quote(&to_hex(member))).collect();
+            format!("members {} {}", quote(key), members.join(" "))
+        },
+        (Some(Value::Hash(hash)), Query::Field(field)) => match hash.get(field) {
+            Some(value) => format!("value {} {}", quote(key), quote(&to_hex(value))),
+            None => format!("not-found {}", quote(key)),
+        },
+        (Some(value), _) => format!("wrong-type {}", value.kind()),
+        (None, Query::Members) => format!("members {}", quote(key)),
+        (None, _) => format!("not-found {}", quote(key)),
+    }
+}
+
+//The confirm_reads function hands a read index the leader has confirmed to the reads that were waiting for it
+//Reads of the leader's own clients get it directly, followers are sent it
+fn confirm_reads(index: u64, waiters: Vec<ReadWaiter>, pending_reads: &mut HashMap<u64, PendingRead>, connections: &mut PeerConnections) {
+    for waiter in waiters {
+        match waiter {
+            ReadWaiter::Local(read_id) => {
+                if let Some(read) = pending_reads.get_mut(&read_id) {
+                    read.index = Some(index);
+                }
+            },
+            ReadWaiter::Remote { from, read_id } => send_to_peer(connections, from, &PeerMessage::ReadIndexResponse { read_id, index: Some(index) }),
+        }
+    }
+}
+
+//The reject_read function tells a read waiting for the leader that its read index could not be confirmed
+fn reject_read(waiter: ReadWaiter, pending_reads: &mut HashMap<u64, PendingRead>, connections: &mut PeerConnections, clients: &Clients) {
+    match waiter {
+        ReadWaiter::Local(read_id) => {
+            if let Some(read) = pending_reads.remove(&read_id) {
+                clients.reply(read.connection, read.request_id, "error the leader could not confirm the read");
+            }
+        },
+        ReadWaiter::Remote { from, read_id } => send_to_peer(connections, from, &PeerMessage::ReadIndexResponse { read_id, index: None }),
+    }
+}
+
+//The answer_reads function answers every linearizable read whose read index has been confirmed and applied
+fn answer_reads(pending_reads: &mut HashMap<u64, PendingRead>, store: &KeyValueStore, clients: &Clients) {
+    let ready: Vec<u64> = pending_reads.iter()
+        .filter(|(_, read)| matches!(read.index, Some(index) if index <= store.applied_index()))
+        .map(|(read_id, _)| *read_id)
+        .collect();
+    for read_id in ready {
+        let read = pending_reads.remove(&read_id).unwrap();
+        clients.reply(read.connection, read.request_id, &read_response(store, &read.key, &read.query));
+    }
+}
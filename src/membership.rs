@@ -0,0 +1,163 @@
+//Imports
+//The members file starts with the same versioned header as the other files in the data directory
+use crate::data_format::{strip_header, with_header};
+//The addresses of the nodes in a configuration
+use omnipaxos_key_value_store::config::NodeAddress;
+//Serde - the membership travels in the stop sign and is stored in the data directory
+use serde::{Serialize, Deserialize};
+//Used for the members file
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+//Name of the file in each configuration's storage directory that lists the members of the configuration
+const MEMBERS_FILE: &str = "members";
+
+//The MembershipChange enum is a change an operator asks for with add-node or remove-node
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum MembershipChange {
+    Add(NodeAddress),
+    Remove(u64),
+}
+
+//The Membership struct is one configuration of the cluster - its id and the address of every node in it
+//A reconfiguration decides a stop sign in the old configuration that carries the membership of the new one as its metadata
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Membership {
+    pub config_id: u32,
+    pub nodes: Vec<NodeAddress>,
+}
+
+impl Membership {
+    //The pids of all nodes in the configuration, in ascending order
+    pub fn pids(&self) -> Vec<u64> {
+        let mut pids: Vec<u64> = self.nodes.iter().map(|node| node.pid).collect();
+        pids.sort_unstable();
+        pids
+    }
+
+    //Every node of the configuration except the given one
+    pub fn peers(&self, pid: u64) -> Vec<&NodeAddress> {
+        self.nodes.iter().filter(|node| node.pid != pid).collect()
+    }
+
+    pub fn contains(&self, pid: u64) -> bool {
+        self.nodes.iter().any(|node| node.pid == pid)
+    }
+
+    //The membership of the next configuration after the change
+    pub fn change(&self, change: MembershipChange) -> Result<Membership, String> {
+        let mut nodes = self.nodes.clone();
+        match change {
+            MembershipChange::Add(address) => {
+                if self.contains(address.pid) {
+                    return Err(format!("node {} is already in the cluster", address.pid));
+                }
+                nodes.push(address);
+            },
+            MembershipChange::Remove(pid) => {
+                if !self.contains(pid) {
+                    return Err(format!("node {} is not in the cluster", pid));
+                }
+                nodes.retain(|node| node.pid != pid);
+                if nodes.is_empty() {
+                    return Err("the last node cannot be removed".to_string());
+                }
+            },
+        }
+        Ok(Membership { config_id: self.config_id + 1, nodes })
+    }
+
+    //The directory a configuration keeps its storage in - the first configuration uses the data directory itself
+    pub fn storage_dir(&self, data_dir: &Path) -> PathBuf {
+        if self.config_id <= 1 {
+            data_dir.to_path_buf()
+        } else {
+            data_dir.join(format!("config-{}", self.config_id))
+        }
+    }
+
+    //Record the membership in the configuration's storage directory, so that a restarted node knows which configuration it is in
+    pub fn save(&self, data_dir: &Path) {
+        let dir = self.storage_dir(data_dir);
+        fs::create_dir_all(&dir).expect("ERROR: Could not create the configuration directory");
+        let temporary_path = dir.join(format!("{}.tmp", MEMBERS_FILE));
+        let bytes = with_header(&bincode::serialize(self).unwrap());
+        let mut file = File::create(&temporary_path).expect("ERROR: Could not write the members file");
+        file.write_all(&bytes).and_then(|_| file.sync_all()).expect("ERROR: Could not write the members file");
+        fs::rename(&temporary_path, dir.join(MEMBERS_FILE)).expect("ERROR: Could not replace the members file");
+    }
+
+    //The latest configuration the node was part of according to its data directory, if it has been reconfigured before
+    //A members file that cannot be read is an error, as the node would otherwise start over in the first configuration
+    pub fn load_latest(data_dir: &Path) -> Result<Option<Membership>, String> {
+        let latest = fs::read_dir(data_dir).into_iter().flatten()
+            .filter_map(|dir_entry| {
+                let name = dir_entry.ok()?.file_name().into_string().ok()?;
+                name.strip_prefix("config-")?.parse::<u32>().ok()
+            })
+            .max();
+        let latest = match latest {
+            Some(latest) => latest,
+            None => return Ok(None),
+        };
+        let path = data_dir.join(format!("config-{}", latest)).join(MEMBERS_FILE);
+        let bytes = fs::read(&path).map_err(|error| format!("could not read {:?}: {}", path, error))?;
+        let bytes = strip_header(&bytes, &path)?;
+        bincode::deserialize(bytes).map(Some).map_err(|error| format!("{:?} is corrupt: {}", path, error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_dir;
+
+    fn membership(config_id: u32, pids: &[u64]) -> Membership {
+        Membership { config_id, nodes: pids.iter().map(|pid| NodeAddress::local(*pid).unwrap()).collect() }
+    }
+
+    #[test]
+    fn loads_the_latest_configuration() {
+        let dir = test_dir("membership-latest");
+        assert!(Membership::load_latest(&dir).unwrap().is_none());
+        membership(2, &[1, 2, 3, 4]).save(&dir);
+        membership(3, &[1, 2, 4]).save(&dir);
+        let latest = Membership::load_latest(&dir).unwrap().unwrap();
+        assert_eq!(latest.config_id, 3);
+        assert_eq!(latest.pids(), vec![1, 2, 4]);
+        assert_eq!(latest.nodes, membership(3, &[1, 2, 4]).nodes);
+        //A data directory that does not exist yet has not been reconfigured either
+        assert!(Membership::load_latest(&dir.join("missing")).unwrap().is_none());
+    }
+
+    #[test]
+    fn refuses_a_corrupt_members_file() {
+        let dir = test_dir("membership-corrupt");
+        membership(2, &[1, 2, 3]).save(&dir);
+        let path = dir.join("config-2").join(MEMBERS_FILE);
+        //A members file from before the header existed
+        fs::write(&path, bincode::serialize(&membership(2, &[1, 2, 3])).unwrap()).unwrap();
+        assert!(Membership::load_latest(&dir).is_err());
+        //A header followed by contents that are not a membership
+        fs::write(&path, with_header(&[0xff; 3])).unwrap();
+        assert!(Membership::load_latest(&dir).is_err());
+        //A configuration directory without a members file
+        fs::remove_file(&path).unwrap();
+        assert!(Membership::load_latest(&dir).is_err());
+    }
+
+    #[test]
+    fn changes_the_membership() {
+        let first = membership(1, &[1, 2, 3]);
+        let added = first.change(MembershipChange::Add(NodeAddress::local(4).unwrap())).unwrap();
+        assert_eq!((added.config_id, added.pids()), (2, vec![1, 2, 3, 4]));
+        assert!(added.change(MembershipChange::Add(NodeAddress::local(4).unwrap())).is_err());
+        let removed = added.change(MembershipChange::Remove(2)).unwrap();
+        assert_eq!((removed.config_id, removed.pids()), (3, vec![1, 3, 4]));
+        assert!(removed.change(MembershipChange::Remove(2)).is_err());
+        assert!(membership(1, &[1]).change(MembershipChange::Remove(1)).is_err());
+    }
+}
@@ -0,0 +1,38 @@
+//Imports
+use crate::{KeyValue, membership::Membership};
+//OmniPaxos library - the SequencePaxos messages and the ballots leaders are elected with
+use omnipaxos_core::{ballot_leader_election::Ballot, messages::Message};
+//Serde - used for serializing the messages
+use serde::{Serialize, Deserialize};
+
+//The PeerMessage enum is everything the nodes send each other on the SequencePaxos connections
+//Besides the SequencePaxos messages themselves this is the traffic needed to confirm linearizable reads with the leader, to keep its lease and to let followers know how far behind they are
+//and to decide how far the log can be trimmed and send snapshots to the nodes that are behind it
+#[derive(Serialize, Deserialize)]
+pub enum PeerMessage {
+    //A SequencePaxos message of the given configuration
+    Paxos { config_id: u32, message: Message<KeyValue, ()> },
+    //A node that was added to the cluster is told the membership of the configuration it is now part of
+    Join { membership: Membership },
+    //A follower asks the leader for a read index, by the follower's id for the read
+    ReadIndexRequest { from: u64, read_id: u64 },
+    //The leader checks with its peers that it is still the leader before handing out a read index, with the ballot it was elected with
+    ReadIndexHeartbeat { from: u64, round: u64, ballot: Ballot },
+    //A peer confirms that the ballot is still the highest it has promised
+    ReadIndexHeartbeatAck { from: u64, round: u64, ballot: Ballot },
+    //The leader's answer to a read index request - None if it is not the leader (any more)
+    ReadIndexResponse { read_id: u64, index: Option<u64> },
+    //The leader renews its lease on every BLE tick, with the ballot it was elected with
+    LeaseHeartbeat { from: u64, round: u64, ballot: Ballot },
+    //A peer grants the lease to the ballot, which is the highest it has promised
+    LeaseAck { from: u64, round: u64, ballot: Ballot },
+    //The leader tells its followers its decided index on every BLE tick, so that they know how far behind they are,
+    //and its compacted index, so that a follower that has not applied up to it knows to ask for a snapshot
+    LeaderProgress { from: u64, decided_idx: u64, compacted_idx: u64 },
+    //A node tells the leader how far its latest snapshot reaches, so that the leader knows how far the log can be trimmed
+    SnapshotTaken { from: u64, index: u64 },
+    //A node whose log is behind the leader's compacted index asks the leader for a chunk of its snapshot
+    SnapshotRequest { from: u64, chunk: u32 },
+    //A chunk of the leader's snapshot, with the index the snapshot reaches and the checksum of the whole snapshot
+    SnapshotChunk { index: u64, chunk: u32, chunks: u32, checksum: u64, data: Vec<u8> },
+}
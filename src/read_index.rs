@@ -0,0 +1,150 @@
+//Imports
+//Each round is tied to the ballot of the leader that started it
+use omnipaxos_core::ballot_leader_election::Ballot;
+//Used for the rounds and the acknowledgements in each of them
+use std::{collections::{HashMap, HashSet}, mem, time};
+
+//The ReadWaiter enum is a linearizable read waiting for the leader to confirm its read index
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReadWaiter {
+    //A read from a client of the leader itself, by the leader's id for the read
+    Local(u64),
+    //A read forwarded by a follower, by the follower's pid and its id for the read
+    Remote { from: u64, read_id: u64 },
+}
+
+//A heartbeat round - the read index it confirms, the ballot it was sent with, the nodes that have acknowledged it so far and the reads waiting for it
+struct ReadRound {
+    index: u64,
+    ballot: Ballot,
+    acks: HashSet<u64>,
+    waiters: Vec<ReadWaiter>,
+    started: time::Instant,
+}
+
+//The ReadIndex struct is the leader's side of linearizable reads
+//The leader notes its decided index, sends a heartbeat with its ballot to every peer, and once a majority (counting itself) has answered
+//that the ballot is still the highest it has promised, no other leader can have decided anything newer and the index is safe to read at
+//The leader only starts rounds once it has decided an entry of its own ballot, as its decided index may miss entries an earlier leader decided before that
+//Reads that arrive while a round is running are collected and confirmed together by the next round
+pub struct ReadIndex {
+    majority: usize,
+    next_round: u64,
+    queued: Vec<ReadWaiter>,
+    rounds: HashMap<u64, ReadRound>,
+}
+
+impl ReadIndex {
+    pub fn new(number_of_nodes: usize) -> ReadIndex {
+        ReadIndex {
+            majority: number_of_nodes / 2 + 1,
+            next_round: 0,
+            queued: vec![],
+            rounds: HashMap::new(),
+        }
+    }
+
+    //Queue a read for the next round
+    pub fn request(&mut self, waiter: ReadWaiter) {
+        self.queued.push(waiter);
+    }
+
+    //Start a round for the queued reads, if there are any, at the leader's current decided index and with its ballot
+    //Returns the round number that has to be sent to the peers in the heartbeat; the leader then acknowledges the round itself
+    pub fn start_round(&mut self, decided_idx: u64, ballot: Ballot) -> Option<u64> {
+        if self.queued.is_empty() {
+            return None;
+        }
+        self.next_round += 1;
+        let waiters = mem::take(&mut self.queued);
+        self.rounds.insert(self.next_round, ReadRound { index: decided_idx, ballot, acks: HashSet::new(), waiters, started: time::Instant::now() });
+        Some(self.next_round)
+    }
+
+    //Record that a node (the leader included) acknowledged a round
+    //An acknowledgement of another ballot than the round was sent with does not count
+    //Returns the confirmed read index and the reads waiting for it once a majority has acknowledged the round
+    pub fn acknowledge(&mut self, round: u64, ballot: Ballot, from: u64) -> Option<(u64, Vec<ReadWaiter>)> {
+        let read_round = self.rounds.get_mut(&round).filter(|read_round| read_round.ballot == ballot)?;
+        read_round.acks.insert(from);
+        if read_round.acks.len() < self.majority {
+            return None;
+        }
+        let read_round = self.rounds.remove(&round).unwrap();
+        Some((read_round.index, read_round.waiters))
+    }
+
+    //Give up on rounds that a majority has not acknowledged in time, e.g. because too many peers are down
+    pub fn expire(&mut self, timeout: time::Duration) -> Vec<ReadWaiter> {
+        let expired: Vec<u64> = self.rounds.iter().filter(|(_, read_round)| read_round.started.elapsed() >= timeout).map(|(round, _)| *round).collect();
+        expired.into_iter().flat_map(|round| self.rounds.remove(&round).unwrap().waiters).collect()
+    }
+
+    //Give up on every read - the node is no longer the leader
+    pub fn abandon(&mut self) -> Vec<ReadWaiter> {
+        let mut waiters: Vec<ReadWaiter> = self.rounds.drain().flat_map(|(_, read_round)| read_round.waiters).collect();
+        waiters.append(&mut self.queued);
+        waiters
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BALLOT: Ballot = Ballot { n: 2, priority: 0, pid: 1 };
+
+    #[test]
+    fn waits_for_a_majority() {
+        let mut read_index = ReadIndex::new(5);
+        assert_eq!(read_index.start_round(3, BALLOT), None);
+        read_index.request(ReadWaiter::Local(1));
+        read_index.request(ReadWaiter::Remote { from: 2, read_id: 7 });
+        let round = read_index.start_round(3, BALLOT).unwrap();
+        //Reads that arrive while the round is running wait for the next one
+        read_index.request(ReadWaiter::Local(2));
+        assert_eq!(read_index.acknowledge(round, BALLOT, 1), None);
+        assert_eq!(read_index.acknowledge(round, BALLOT, 2), None);
+        //The same node acknowledging twice still counts once
+        assert_eq!(read_index.acknowledge(round, BALLOT, 2), None);
+        let (index, waiters) = read_index.acknowledge(round, BALLOT, 4).unwrap();
+        assert_eq!(index, 3);
+        assert_eq!(waiters, vec![ReadWaiter::Local(1), ReadWaiter::Remote { from: 2, read_id: 7 }]);
+        //A late acknowledgement of a confirmed round does nothing
+        assert_eq!(read_index.acknowledge(round, BALLOT, 5), None);
+        let next = read_index.start_round(4, BALLOT).unwrap();
+        assert_ne!(next, round);
+        assert_eq!(read_index.acknowledge(next, BALLOT, 1), None);
+        assert_eq!(read_index.acknowledge(next, BALLOT, 3), None);
+        assert_eq!(read_index.acknowledge(next, BALLOT, 5), Some((4, vec![ReadWaiter::Local(2)])));
+    }
+
+    #[test]
+    fn ignores_acknowledgements_of_another_ballot() {
+        let stale = Ballot { n: 1, priority: 0, pid: 1 };
+        let mut read_index = ReadIndex::new(3);
+        read_index.request(ReadWaiter::Local(1));
+        let round = read_index.start_round(5, BALLOT).unwrap();
+        assert_eq!(read_index.acknowledge(round, stale, 1), None);
+        assert_eq!(read_index.acknowledge(round, stale, 2), None);
+        assert_eq!(read_index.acknowledge(round, stale, 3), None);
+        assert_eq!(read_index.acknowledge(round, BALLOT, 1), None);
+        assert_eq!(read_index.acknowledge(round, BALLOT, 2), Some((5, vec![ReadWaiter::Local(1)])));
+    }
+
+    #[test]
+    fn abandon_rejects_every_waiter() {
+        let mut read_index = ReadIndex::new(3);
+        read_index.request(ReadWaiter::Local(1));
+        let round = read_index.start_round(5, BALLOT).unwrap();
+        read_index.request(ReadWaiter::Remote { from: 3, read_id: 9 });
+        let mut waiters = read_index.abandon();
+        waiters.sort_by_key(|waiter| format!("{:?}", waiter));
+        assert_eq!(waiters, vec![ReadWaiter::Local(1), ReadWaiter::Remote { from: 3, read_id: 9 }]);
+        //Nothing is left to confirm or to start a round for
+        assert_eq!(read_index.acknowledge(round, BALLOT, 1), None);
+        assert_eq!(read_index.acknowledge(round, BALLOT, 2), None);
+        assert_eq!(read_index.start_round(6, BALLOT), None);
+        assert!(read_index.abandon().is_empty());
+    }
+}
@@ -0,0 +1,249 @@
+//Imports
+use crate::{KeyValue, data_format::{strip_header, with_header}, state_machine::KeyValueStore};
+//Snapshots are written to disk on a blocking thread, which tells the SequencePaxos handler once the snapshot is durable
+use tokio::{sync::mpsc, task};
+//Used for the snapshot file and the snapshot indices reported by the peers
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+//Name of the snapshot file inside the data directory
+const SNAPSHOT_FILE: &str = "snapshot";
+
+//The Snapshotter struct decides when to snapshot the key-value store and how far the log can be trimmed
+//Every node snapshots its own store once enough entries (or bytes) have been applied since its last snapshot, and tells the leader
+//The leader trims the log up to the lowest snapshot index of all nodes, so no node ever loses entries it has not snapshotted
+//A snapshot is written to the data directory in the background, and only counts once it is durable
+pub struct Snapshotter {
+    //Where the snapshot is stored - without a data directory the snapshot is only used to decide how far to trim
+    dir: Option<PathBuf>,
+    //The SequencePaxos handler is sent "snapshot_saved" with the directory and index once a snapshot has been written
+    sender: mpsc::Sender<(&'static str, Vec<u8>)>,
+    //Only one snapshot is written at a time - one taken in the meantime waits, and is replaced if another one is taken before it is written
+    saving: bool,
+    waiting: Option<KeyValueStore>,
+    //Thresholds for taking a new snapshot; 0 turns a threshold off
+    max_entries: u64,
+    max_bytes: u64,
+    //Entries and bytes applied since the last snapshot
+    entries: u64,
+    bytes: u64,
+    //The log index the latest snapshot reaches
+    index: u64,
+    //The snapshot indices the peers have reported, while this node is the leader, and how far the log has been trimmed
+    reports: HashMap<u64, u64>,
+    trimmed: u64,
+}
+
+impl Snapshotter {
+    pub fn new(dir: Option<PathBuf>, max_entries: u64, max_bytes: u64, sender: mpsc::Sender<(&'static str, Vec<u8>)>) -> Snapshotter {
+        Snapshotter {
+            dir,
+            sender,
+            saving: false,
+            waiting: None,
+            max_entries,
+            max_bytes,
+            entries: 0,
+            bytes: 0,
+            index: 0,
+            reports: HashMap::new(),
+            trimmed: 0,
+        }
+    }
+
+    //A snapshotter with the same thresholds for a new configuration, which keeps its storage in another directory
+    pub fn restart(&self, dir: Option<PathBuf>) -> Snapshotter {
+        Snapshotter::new(dir, self.max_entries, self.max_bytes, self.sender.clone())
+    }
+
+    //Load the snapshot a previous run left in the data directory, if there is one
+    pub fn load(&mut self) -> Option<KeyValueStore> {
+        let path = self.dir.as_ref()?.join(SNAPSHOT_FILE);
+        let bytes = fs::read(&path).ok()?;
+        let bytes = strip_header(&bytes, &path).unwrap_or_else(|error| panic!("ERROR: {}", error));
+        let store: KeyValueStore = bincode::deserialize(bytes).expect("ERROR: The snapshot file is corrupt");
+        self.index = store.applied_index();
+        Some(store)
+    }
+
+    //Count an applied entry towards the thresholds
+    pub fn record(&mut self, entry: &KeyValue) {
+        self.entries += 1;
+        self.bytes += bincode::serialized_size(entry).unwrap();
+    }
+
+    //Whether enough has been applied since the last snapshot to take a new one
+    pub fn is_due(&self) -> bool {
+        (self.max_entries > 0 && self.entries >= self.max_entries) || (self.max_bytes > 0 && self.bytes >= self.max_bytes)
+    }
+
+    //Snapshot the store; returns the log index the snapshot reaches, or None if it is being written to the data directory
+    //In that case the index is returned by saved once the snapshot is durable
+    pub fn take(&mut self, store: &KeyValueStore) -> Option<u64> {
+        self.entries = 0;
+        self.bytes = 0;
+        if self.dir.is_none() {
+            self.index = store.applied_index();
+            return Some(self.index);
+        }
+        if self.saving {
+            self.waiting = Some(store.clone());
+        } else {
+            self.save(store.clone());
+        }
+        None
+    }
+
+    //Record that a snapshot has been written, and start writing the one that waited for it, if any
+    //Returns the log index the snapshot reaches - None if it was written for an earlier configuration, which keeps its storage in another directory
+    pub fn saved(&mut self, dir: &Path, index: u64) -> Option<u64> {
+        if self.dir.as_deref() != Some(dir) {
+            return None;
+        }
+        self.saving = false;
+        self.index = self.index.max(index);
+        if let Some(store) = self.waiting.take() {
+            self.save(store);
+        }
+        Some(self.index)
+    }
+
+    //Write a snapshot on a blocking thread, so that the node keeps handling messages in the meantime
+    fn save(&mut self, store: KeyValueStore) {
+        let dir = self.dir.clone().unwrap();
+        let sender = self.sender.clone();
+        self.saving = true;
+        task::spawn_blocking(move || {
+            persist(&dir, &store);
+            let _ = sender.blocking_send(("snapshot_saved", bincode::serialize(&(dir, store.applied_index())).unwrap()));
+        });
+    }
+
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+
+    //Record the snapshot index a peer reported
+    pub fn report(&mut self, from: u64, index: u64) {
+        let reported = self.reports.entry(from).or_insert(0);
+        *reported = (*reported).max(index);
+    }
+
+    //The index the leader can trim the log up to - the lowest snapshot index of all nodes, if that is further than the last trim
+    pub fn trim_index(&self, peers: &[u64]) -> Option<u64> {
+        let mut index = self.index;
+        for peer in peers {
+            //A peer that has not reported a snapshot yet still needs the whole log
+            index = index.min(*self.reports.get(peer)?);
+        }
+        if index > self.trimmed {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    //Record that the log has been trimmed up to the index
+    pub fn trimmed(&mut self, index: u64) {
+        self.trimmed = index;
+    }
+}
+
+//Write the snapshot to a temporary file first and then rename it, so that a crash never leaves a half-written snapshot
+fn persist(dir: &Path, store: &KeyValueStore) {
+    let temporary_path = dir.join(format!("{}.tmp", SNAPSHOT_FILE));
+    let bytes = with_header(&bincode::serialize(store).unwrap());
+    let mut file = File::create(&temporary_path).expect("ERROR: Could not write the snapshot file");
+    file.write_all(&bytes).and_then(|_| file.sync_all()).expect("ERROR: Could not write the snapshot file");
+    fs::rename(&temporary_path, dir.join(SNAPSHOT_FILE)).expect("ERROR: Could not replace the snapshot file");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Operation, test_support::test_dir};
+
+    fn store(entries: u64) -> KeyValueStore {
+        let mut store = KeyValueStore::new();
+        for index in 0..entries {
+            store.apply(&KeyValue { key: format!("key{}", index % 3), operation: Operation::Put(vec![index as u8]), origin: 1, request_id: index });
+        }
+        store
+    }
+
+    #[test]
+    fn persists_and_loads() {
+        let dir = test_dir("snapshot-load");
+        let (sender, _receiver) = mpsc::channel(1);
+        let mut snapshotter = Snapshotter::new(Some(dir.clone()), 10, 0, sender.clone());
+        assert!(snapshotter.load().is_none());
+        persist(&dir, &store(5));
+        let mut snapshotter = Snapshotter::new(Some(dir.clone()), 10, 0, sender);
+        let loaded = snapshotter.load().unwrap();
+        assert_eq!(loaded.applied_index(), 5);
+        assert_eq!(loaded.get("key1"), store(5).get("key1"));
+        assert_eq!(snapshotter.index(), 5);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn takes_snapshots_without_a_directory_right_away() {
+        let (sender, _receiver) = mpsc::channel(1);
+        let mut snapshotter = Snapshotter::new(None, 2, 0, sender);
+        let store = store(2);
+        for entry in 0..2 {
+            assert!(!snapshotter.is_due());
+            snapshotter.record(&KeyValue { key: "key".to_string(), operation: Operation::Put(vec![entry]), origin: 1, request_id: 0 });
+        }
+        assert!(snapshotter.is_due());
+        assert_eq!(snapshotter.take(&store), Some(2));
+        assert!(!snapshotter.is_due());
+    }
+
+    #[tokio::test]
+    async fn writes_one_snapshot_at_a_time() {
+        let dir = test_dir("snapshot-background");
+        let (sender, mut receiver) = mpsc::channel(4);
+        let mut snapshotter = Snapshotter::new(Some(dir.clone()), 10, 0, sender);
+        assert_eq!(snapshotter.take(&store(3)), None);
+        //Taken while the first one is written, so it waits - and is replaced by the one taken after it
+        assert_eq!(snapshotter.take(&store(4)), None);
+        assert_eq!(snapshotter.take(&store(6)), None);
+        assert_eq!(snapshotter.index(), 0);
+
+        let mut saved = vec![];
+        while saved.len() < 2 {
+            let (name, message) = receiver.recv().await.unwrap();
+            assert_eq!(name, "snapshot_saved");
+            let (saved_dir, index): (PathBuf, u64) = bincode::deserialize(&message).unwrap();
+            saved.push(snapshotter.saved(&saved_dir, index).unwrap());
+        }
+        assert_eq!(saved, [3, 6]);
+        assert_eq!(Snapshotter::new(Some(dir.clone()), 10, 0, mpsc::channel(1).0).load().unwrap().applied_index(), 6);
+        //A snapshot written for an earlier configuration does not count
+        assert_eq!(snapshotter.saved(Path::new("elsewhere"), 9), None);
+        assert_eq!(snapshotter.index(), 6);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn trims_up_to_the_oldest_snapshot() {
+        let (sender, _receiver) = mpsc::channel(1);
+        let mut snapshotter = Snapshotter::new(None, 10, 0, sender);
+        snapshotter.take(&store(8));
+        snapshotter.report(2, 5);
+        assert_eq!(snapshotter.trim_index(&[2, 3]), None);
+        snapshotter.report(3, 7);
+        assert_eq!(snapshotter.trim_index(&[2, 3]), Some(5));
+        snapshotter.trimmed(5);
+        assert_eq!(snapshotter.trim_index(&[2, 3]), None);
+        //Reports never go back
+        snapshotter.report(2, 4);
+        snapshotter.report(2, 9);
+        assert_eq!(snapshotter.trim_index(&[2, 3]), Some(7));
+    }
+}
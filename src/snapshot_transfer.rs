@@ -0,0 +1,244 @@
+//Imports
+use crate::{peer_message::PeerMessage, state_machine::KeyValueStore};
+//Used for noticing transfers that have stalled
+use std::time;
+
+//Size of the chunks a snapshot is sent in, well below the maximum frame size
+const CHUNK_SIZE: usize = 64 * 1024;
+//A transfer that has not made progress for this long is started over
+const TRANSFER_TIMEOUT: time::Duration = time::Duration::from_secs(2);
+
+//A snapshot being received - where it comes from, what it covers and the chunks received so far
+struct IncomingSnapshot {
+    leader: u64,
+    index: u64,
+    chunks: u32,
+    checksum: u64,
+    bytes: Vec<u8>,
+    //The chunk that has been asked for and not received yet
+    next_chunk: u32,
+    last_activity: time::Instant,
+}
+
+//What a received chunk means for the transfer
+pub enum Received {
+    //Ask for this chunk next
+    Request(u32),
+    //The snapshot is complete and its checksum matches
+    Complete(KeyValueStore),
+    //The chunk did not belong to the transfer, or the checksum did not match - start over
+    Restart,
+    //A duplicate or late chunk
+    Ignore,
+}
+
+//The SnapshotTransfer struct moves a full key-value snapshot from the leader to a node whose log is behind the leader's compacted index
+//Such a node cannot catch up by replaying the log, since the entries it is missing have been trimmed
+//The node asks for one chunk at a time, so a large snapshot never fills up the queue to the peer; chunk 0 makes the leader take a new snapshot
+//Once every chunk has arrived the node checks the checksum, installs the snapshot and carries on with normal log replication
+#[derive(Default)]
+pub struct SnapshotTransfer {
+    //The leader's side - the serialized snapshot being sent, with its index and checksum
+    outgoing: Option<(u64, Vec<u8>, u64)>,
+    //The receiving node's side
+    incoming: Option<IncomingSnapshot>,
+    //A node that joins the cluster starts without the key-value pairs, so it needs a snapshot before it can apply anything
+    required: bool,
+}
+
+impl SnapshotTransfer {
+    //Whether the node has to ask the leader for (a chunk of) a snapshot, and if so who to ask and for which chunk
+    //Called on every tick; a transfer from a leader that is gone, or one that has stalled, is started over
+    pub fn next_request(&mut self, leader: Option<u64>, behind: bool) -> Option<(u64, u32)> {
+        let leader = leader?;
+        if !behind && !self.required {
+            self.incoming = None;
+            return None;
+        }
+        match &mut self.incoming {
+            Some(incoming) if incoming.leader == leader && incoming.last_activity.elapsed() < TRANSFER_TIMEOUT => None,
+            Some(incoming) if incoming.leader == leader && incoming.next_chunk > 0 => {
+                //Ask for the missing chunk again
+                incoming.last_activity = time::Instant::now();
+                Some((leader, incoming.next_chunk))
+            },
+            _ => {
+                println!("The key-value pairs are behind the leader - asking node {} for a snapshot", leader);
+                self.incoming = Some(IncomingSnapshot { leader, index: 0, chunks: 0, checksum: 0, bytes: vec![], next_chunk: 0, last_activity: time::Instant::now() });
+                Some((leader, 0))
+            },
+        }
+    }
+
+    //Ask for a snapshot even though the log is not behind
+    pub fn require(&mut self) {
+        self.required = true;
+    }
+
+    pub fn is_required(&self) -> bool {
+        self.required
+    }
+
+    //Record that a received snapshot has been installed
+    pub fn installed(&mut self) {
+        self.required = false;
+    }
+
+    //Answer a request for a chunk of the leader's snapshot; chunk 0 snapshots the store anew
+    pub fn serve(&mut self, store: &KeyValueStore, chunk: u32) -> Option<PeerMessage> {
+        if chunk == 0 || self.outgoing.is_none() {
+            let bytes = bincode::serialize(store).unwrap();
+            let checksum = checksum(&bytes);
+            self.outgoing = Some((store.applied_index(), bytes, checksum));
+        }
+        let (index, bytes, checksum) = self.outgoing.as_ref().unwrap();
+        let chunks = bytes.len().div_ceil(CHUNK_SIZE).max(1) as u32;
+        if chunk >= chunks {
+            return None;
+        }
+        let start = chunk as usize * CHUNK_SIZE;
+        let end = (start + CHUNK_SIZE).min(bytes.len());
+        Some(PeerMessage::SnapshotChunk { index: *index, chunk, chunks, checksum: *checksum, data: bytes[start..end].to_vec() })
+    }
+
+    //Take in a chunk from the leader
+    pub fn receive(&mut self, index: u64, chunk: u32, chunks: u32, checksum_of_snapshot: u64, mut data: Vec<u8>) -> Received {
+        let incoming = match &mut self.incoming {
+            Some(incoming) => incoming,
+            //A late chunk of a transfer that has already finished
+            None => return Received::Ignore,
+        };
+        //Lost chunks are asked for again when the transfer stalls
+        if chunk != incoming.next_chunk {
+            return Received::Ignore;
+        }
+        if chunk == 0 {
+            incoming.index = index;
+            incoming.chunks = chunks;
+            incoming.checksum = checksum_of_snapshot;
+            incoming.bytes.clear();
+        } else if index != incoming.index || chunks != incoming.chunks || checksum_of_snapshot != incoming.checksum {
+            //The leader took a new snapshot in the middle of the transfer
+            self.incoming = None;
+            return Received::Restart;
+        }
+        incoming.bytes.append(&mut data);
+        incoming.next_chunk += 1;
+        incoming.last_activity = time::Instant::now();
+        if incoming.next_chunk < incoming.chunks {
+            return Received::Request(incoming.next_chunk);
+        }
+        let incoming = self.incoming.take().unwrap();
+        if checksum(&incoming.bytes) != incoming.checksum {
+            println!("ERROR: The snapshot from node {} does not match its checksum", incoming.leader);
+            return Received::Restart;
+        }
+        match bincode::deserialize(&incoming.bytes) {
+            Ok(store) => Received::Complete(store),
+            Err(error) => {
+                println!("ERROR: Could not read the snapshot from node {} - {}", incoming.leader, error);
+                Received::Restart
+            },
+        }
+    }
+}
+
+//The checksum function computes the 64 bit FNV-1a hash of the snapshot
+fn checksum(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KeyValue, Operation};
+
+    //A store a little over three chunks in size
+    fn store() -> KeyValueStore {
+        let mut store = KeyValueStore::new();
+        for index in 0..20 {
+            store.apply(&KeyValue { key: format!("key{}", index), operation: Operation::Put(vec![index as u8; 10 * 1024]), origin: 1, request_id: index });
+        }
+        store
+    }
+
+    //The chunk the leader sends, as the arguments of receive
+    fn chunk(message: PeerMessage) -> (u64, u32, u32, u64, Vec<u8>) {
+        match message {
+            PeerMessage::SnapshotChunk { index, chunk, chunks, checksum, data } => (index, chunk, chunks, checksum, data),
+            _ => panic!("not a snapshot chunk"),
+        }
+    }
+
+    #[test]
+    fn transfers_in_chunks() {
+        let store = store();
+        let mut leader = SnapshotTransfer::default();
+        let mut node = SnapshotTransfer::default();
+        assert_eq!(node.next_request(Some(1), false), None);
+        assert_eq!(node.next_request(Some(1), true), Some((1, 0)));
+        //Nothing is asked for again until the transfer stalls
+        assert_eq!(node.next_request(Some(1), true), None);
+        let mut next = 0;
+        let installed = loop {
+            let (index, chunk, chunks, checksum, data) = chunk(leader.serve(&store, next).unwrap());
+            assert_eq!(chunks, 4);
+            assert!(data.len() <= CHUNK_SIZE);
+            match node.receive(index, chunk, chunks, checksum, data) {
+                Received::Request(chunk) => next = chunk,
+                Received::Complete(installed) => break installed,
+                _ => panic!("the transfer did not complete"),
+            }
+        };
+        assert_eq!(next, 3);
+        assert!(leader.serve(&store, 4).is_none());
+        assert_eq!(installed.applied_index(), store.applied_index());
+        assert_eq!(installed.get("key7"), store.get("key7"));
+    }
+
+    #[test]
+    fn restarts_on_a_bad_checksum() {
+        let store = store();
+        let mut leader = SnapshotTransfer::default();
+        let mut node = SnapshotTransfer::default();
+        node.next_request(Some(1), true);
+        for next in 0..4 {
+            let (index, chunk, chunks, checksum, mut data) = chunk(leader.serve(&store, next).unwrap());
+            if next == 2 {
+                data[0] ^= 1;
+            }
+            match node.receive(index, chunk, chunks, checksum, data) {
+                Received::Request(_) => assert!(next < 3),
+                Received::Restart => assert_eq!(next, 3),
+                _ => panic!("the corrupted snapshot was not refused"),
+            }
+        }
+        //The transfer starts over from the first chunk
+        assert_eq!(node.next_request(Some(1), true), Some((1, 0)));
+    }
+
+    #[test]
+    fn ignores_stray_chunks_and_restarts_on_a_new_snapshot() {
+        let mut leader = SnapshotTransfer::default();
+        let mut node = SnapshotTransfer::default();
+        let (index, chunk_number, chunks, checksum, data) = chunk(leader.serve(&store(), 0).unwrap());
+        //No transfer is going on
+        assert!(matches!(node.receive(index, chunk_number, chunks, checksum, data.clone()), Received::Ignore));
+        node.next_request(Some(1), true);
+        assert!(matches!(node.receive(index, chunk_number, chunks, checksum, data), Received::Request(1)));
+        //A chunk other than the one asked for
+        let (index, chunk_number, chunks, checksum, data) = chunk(leader.serve(&store(), 2).unwrap());
+        assert!(matches!(node.receive(index, chunk_number, chunks, checksum, data), Received::Ignore));
+        //The leader took a new snapshot in the meantime
+        let mut newer = store();
+        newer.apply(&KeyValue { key: "newer".to_string(), operation: Operation::Delete, origin: 1, request_id: 0 });
+        leader.serve(&newer, 0);
+        let (index, chunk_number, chunks, checksum, data) = chunk(leader.serve(&newer, 1).unwrap());
+        assert!(matches!(node.receive(index, chunk_number, chunks, checksum, data), Received::Restart));
+    }
+}
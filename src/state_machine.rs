@@ -0,0 +1,363 @@
+//Imports
+use crate::{Condition, Guard, KeyValue, Operation, TransactionWrite};
+//Serde - the store is serialized as a whole when it is snapshotted
+use serde::{Serialize, Deserialize};
+//HashMap - used for the materialized key-value pairs; the ordered collections keep set members and hash fields in the same order on every replica
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+
+//The outcome of applying an entry, which is reported to the client that proposed it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WriteResult {
+    //The write took effect
+    Applied,
+    //A conditional write whose condition did not hold - holds the key's actual value (None if the key does not exist)
+    ConditionFailed(Option<Vec<u8>>),
+    //An increment or decrement that took effect - holds the resulting value
+    Counter(u64),
+    //An increment or decrement that would overflow or go below zero, so the key was left unchanged - holds its current value
+    OutOfRange(Option<u64>),
+    //An increment or decrement of a value that is not a number, which was left unchanged
+    NotANumber,
+    //A push that took effect - holds the length of the list afterwards
+    Length(u64),
+    //A pop - holds the value taken off the list (None if the list was empty or the key does not exist)
+    Popped(Option<Vec<u8>>),
+    //An operation for a different type than the key has, which was left unchanged - holds the key's type
+    WrongType(&'static str),
+    //A transaction - whether its guards held, and so whether its writes took effect
+    Transaction(bool),
+}
+
+//The Value enum is what a key holds - its type is set by the first operation on the key and stays until the key is deleted or put
+//A list, set or hash that becomes empty is removed, the same as a deleted key
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Value {
+    //Bytes, as written by put - counters are strings that hold a decimal number
+    String(Vec<u8>),
+    List(VecDeque<Vec<u8>>),
+    Set(BTreeSet<Vec<u8>>),
+    Hash(BTreeMap<String, Vec<u8>>),
+}
+
+impl Value {
+    //The name of the value's type, as reported to clients
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Set(_) => "set",
+            Value::Hash(_) => "hash",
+        }
+    }
+}
+
+//The KeyValueStore struct is the materialized state of the replicated log
+//Decided KeyValue entries are applied to the map in log order, and applied_index records how far into the decided log the map reaches
+//Values are arbitrary bytes or collections of them; counters keep their value as a decimal number in text, so that it reads the same as any other value
+#[derive(Clone, Serialize, Deserialize)]
+pub struct KeyValueStore {
+    map: HashMap<String, Value>,
+    applied_index: u64,
+}
+
+impl KeyValueStore {
+    //Create an empty store that has not applied any entries yet
+    pub fn new() -> KeyValueStore {
+        KeyValueStore {
+            map: HashMap::new(),
+            applied_index: 0,
+        }
+    }
+
+    //The index of the next decided entry to apply - everything before it is reflected in the map
+    pub fn applied_index(&self) -> u64 {
+        self.applied_index
+    }
+
+    //Look up the value of a key in the materialized state
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.map.get(key)
+    }
+
+    //Apply a decided entry; later entries overwrite earlier ones for the same key and tombstones remove the key
+    //Conditions and types are checked here rather than when the entry is proposed, so every replica reaches the same result
+    pub fn apply(&mut self, kv: &KeyValue) -> WriteResult {
+        self.applied_index += 1;
+        //Conditions and counters work on strings; any other type makes them fail without changing the key
+        let current = match self.get(&kv.key) {
+            Some(Value::String(value)) => Some(value.clone()),
+            Some(other) if is_string_operation(&kv.operation) => return WriteResult::WrongType(other.kind()),
+            _ => None,
+        };
+        match &kv.operation {
+            //A put replaces the key whatever its type was
+            Operation::Put(value) => {
+                self.map.insert(kv.key.clone(), Value::String(value.clone()));
+                WriteResult::Applied
+            },
+            Operation::Delete => {
+                self.map.remove(&kv.key);
+                WriteResult::Applied
+            },
+            Operation::CompareAndSwap { expected, new } => {
+                if current.as_ref() == Some(expected) {
+                    self.map.insert(kv.key.clone(), Value::String(new.clone()));
+                    WriteResult::Applied
+                } else {
+                    WriteResult::ConditionFailed(current)
+                }
+            },
+            Operation::PutIfAbsent(value) => {
+                if !self.map.contains_key(&kv.key) {
+                    self.map.insert(kv.key.clone(), Value::String(value.clone()));
+                    WriteResult::Applied
+                } else {
+                    WriteResult::ConditionFailed(current)
+                }
+            },
+            //Counters - a missing key counts as 0
+            Operation::Increment(delta) => match counter(current.as_deref()) {
+                Some(number) => self.update_counter(&kv.key, number.checked_add(*delta), current.as_ref().map(|_| number)),
+                None => WriteResult::NotANumber,
+            },
+            Operation::Decrement(delta) => match counter(current.as_deref()) {
+                Some(number) => self.update_counter(&kv.key, number.checked_sub(*delta), current.as_ref().map(|_| number)),
+                None => WriteResult::NotANumber,
+            },
+            //Lists - a missing key is an empty list
+            Operation::ListPush { front, value } => match self.map.entry(kv.key.clone()).or_insert_with(|| Value::List(VecDeque::new())) {
+                Value::List(list) => {
+                    if *front {
+                        list.push_front(value.clone());
+                    } else {
+                        list.push_back(value.clone());
+                    }
+                    WriteResult::Length(list.len() as u64)
+                },
+                other => WriteResult::WrongType(other.kind()),
+            },
+            Operation::ListPop { front } => {
+                let (popped, empty) = match self.map.get_mut(&kv.key) {
+                    Some(Value::List(list)) => (if *front { list.pop_front() } else { list.pop_back() }, list.is_empty()),
+                    Some(other) => return WriteResult::WrongType(other.kind()),
+                    None => (None, false),
+                };
+                if empty {
+                    self.map.remove(&kv.key);
+                }
+                WriteResult::Popped(popped)
+            },
+            //Sets - a missing key is an empty set
+            Operation::SetAdd(member) => match self.map.entry(kv.key.clone()).or_insert_with(|| Value::Set(BTreeSet::new())) {
+                Value::Set(set) => {
+                    set.insert(member.clone());
+                    WriteResult::Applied
+                },
+                other => WriteResult::WrongType(other.kind()),
+            },
+            Operation::SetRemove(member) => {
+                let empty = match self.map.get_mut(&kv.key) {
+                    Some(Value::Set(set)) => {
+                        set.remove(member);
+                        set.is_empty()
+                    },
+                    Some(other) => return WriteResult::WrongType(other.kind()),
+                    None => false,
+                };
+                if empty {
+                    self.map.remove(&kv.key);
+                }
+                WriteResult::Applied
+            },
+            //Hashes - a missing key is an empty hash
+            Operation::HashSet { field, value } => match self.map.entry(kv.key.clone()).or_insert_with(|| Value::Hash(BTreeMap::new())) {
+                Value::Hash(hash) => {
+                    hash.insert(field.clone(), value.clone());
+                    WriteResult::Applied
+                },
+                other => WriteResult::WrongType(other.kind()),
+            },
+            Operation::Noop => WriteResult::Applied,
+            //Transactions - every guard is checked before anything is written, so the writes see none of each other's effects on the guards
+            Operation::Transaction { guards, writes } => {
+                if !guards.iter().all(|guard| self.holds(guard)) {
+                    return WriteResult::Transaction(false);
+                }
+                for write in writes {
+                    match write {
+                        TransactionWrite::Put(key, value) => {
+                            self.map.insert(key.clone(), Value::String(value.clone()));
+                        },
+                        TransactionWrite::Delete(key) => {
+                            self.map.remove(key);
+                        },
+                    }
+                }
+                WriteResult::Transaction(true)
+            },
+        }
+    }
+
+    //Whether a guard of a transaction holds - only strings can equal a value
+    fn holds(&self, guard: &Guard) -> bool {
+        let current = self.get(&guard.key);
+        match &guard.condition {
+            Condition::Equals(expected) => matches!(current, Some(Value::String(value)) if value == expected),
+            Condition::NotEquals(expected) => !matches!(current, Some(Value::String(value)) if value == expected),
+            Condition::Exists => current.is_some(),
+            Condition::Absent => current.is_none(),
+        }
+    }
+
+    //Store the new value of a counter, or leave the key untouched if the new value is out of range
+    fn update_counter(&mut self, key: &str, new: Option<u64>, current: Option<u64>) -> WriteResult {
+        match new {
+            Some(value) => {
+                self.map.insert(key.to_string(), Value::String(value.to_string().into_bytes()));
+                WriteResult::Counter(value)
+            },
+            None => WriteResult::OutOfRange(current),
+        }
+    }
+
+    //Start following the log of a new configuration, which starts from index 0 again; the key-value pairs are kept
+    pub fn start_configuration(&mut self) {
+        self.applied_index = 0;
+    }
+
+    //Skip a decided log entry that does not carry a key-value pair, so that applied_index keeps following the log
+    pub fn skip(&mut self) {
+        self.applied_index += 1;
+    }
+}
+
+//The is_string_operation function tells whether an operation only works on a key that holds a string (or does not exist)
+fn is_string_operation(operation: &Operation) -> bool {
+    matches!(operation, Operation::CompareAndSwap { .. } | Operation::PutIfAbsent(_) | Operation::Increment(_) | Operation::Decrement(_))
+}
+
+//The counter function reads a value as a counter - 0 if the key does not exist, None if the value is not a number
+fn counter(value: Option<&[u8]>) -> Option<u64> {
+    match value {
+        Some(value) => std::str::from_utf8(value).ok()?.parse().ok(),
+        None => Some(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(store: &mut KeyValueStore, key: &str, operation: Operation) -> WriteResult {
+        store.apply(&KeyValue { key: key.to_string(), operation, origin: 1, request_id: 0 })
+    }
+
+    fn string(value: &str) -> Value {
+        Value::String(value.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn puts_deletes_and_conditional_writes() {
+        let mut store = KeyValueStore::new();
+        assert_eq!(apply(&mut store, "a", Operation::Put(b"1".to_vec())), WriteResult::Applied);
+        assert_eq!(store.get("a"), Some(&string("1")));
+        assert_eq!(apply(&mut store, "a", Operation::CompareAndSwap { expected: b"2".to_vec(), new: b"3".to_vec() }), WriteResult::ConditionFailed(Some(b"1".to_vec())));
+        assert_eq!(apply(&mut store, "a", Operation::CompareAndSwap { expected: b"1".to_vec(), new: b"3".to_vec() }), WriteResult::Applied);
+        assert_eq!(apply(&mut store, "a", Operation::PutIfAbsent(b"4".to_vec())), WriteResult::ConditionFailed(Some(b"3".to_vec())));
+        assert_eq!(apply(&mut store, "a", Operation::Delete), WriteResult::Applied);
+        assert_eq!(store.get("a"), None);
+        assert_eq!(apply(&mut store, "a", Operation::PutIfAbsent(b"4".to_vec())), WriteResult::Applied);
+        assert_eq!(store.get("a"), Some(&string("4")));
+        //Every entry counts towards the applied index, whether it took effect or not
+        assert_eq!(store.applied_index(), 6);
+    }
+
+    #[test]
+    fn counters() {
+        let mut store = KeyValueStore::new();
+        assert_eq!(apply(&mut store, "n", Operation::Increment(5)), WriteResult::Counter(5));
+        assert_eq!(apply(&mut store, "n", Operation::Decrement(6)), WriteResult::OutOfRange(Some(5)));
+        assert_eq!(apply(&mut store, "missing", Operation::Decrement(1)), WriteResult::OutOfRange(None));
+        assert_eq!(apply(&mut store, "n", Operation::Decrement(5)), WriteResult::Counter(0));
+        assert_eq!(store.get("n"), Some(&string("0")));
+        apply(&mut store, "text", Operation::Put(b"abc".to_vec()));
+        assert_eq!(apply(&mut store, "text", Operation::Increment(1)), WriteResult::NotANumber);
+        assert_eq!(store.get("text"), Some(&string("abc")));
+        apply(&mut store, "max", Operation::Put(u64::MAX.to_string().into_bytes()));
+        assert_eq!(apply(&mut store, "max", Operation::Increment(1)), WriteResult::OutOfRange(Some(u64::MAX)));
+    }
+
+    #[test]
+    fn lists_sets_and_hashes() {
+        let mut store = KeyValueStore::new();
+        assert_eq!(apply(&mut store, "l", Operation::ListPush { front: false, value: b"b".to_vec() }), WriteResult::Length(1));
+        assert_eq!(apply(&mut store, "l", Operation::ListPush { front: true, value: b"a".to_vec() }), WriteResult::Length(2));
+        assert_eq!(apply(&mut store, "l", Operation::ListPop { front: false }), WriteResult::Popped(Some(b"b".to_vec())));
+        assert_eq!(apply(&mut store, "l", Operation::ListPop { front: false }), WriteResult::Popped(Some(b"a".to_vec())));
+        //An emptied list is removed
+        assert_eq!(store.get("l"), None);
+        assert_eq!(apply(&mut store, "l", Operation::ListPop { front: true }), WriteResult::Popped(None));
+
+        apply(&mut store, "s", Operation::SetAdd(b"y".to_vec()));
+        apply(&mut store, "s", Operation::SetAdd(b"x".to_vec()));
+        apply(&mut store, "s", Operation::SetAdd(b"x".to_vec()));
+        assert_eq!(store.get("s"), Some(&Value::Set([b"x".to_vec(), b"y".to_vec()].into_iter().collect())));
+        apply(&mut store, "s", Operation::SetRemove(b"x".to_vec()));
+        apply(&mut store, "s", Operation::SetRemove(b"y".to_vec()));
+        assert_eq!(store.get("s"), None);
+
+        apply(&mut store, "h", Operation::HashSet { field: "f".to_string(), value: b"1".to_vec() });
+        apply(&mut store, "h", Operation::HashSet { field: "f".to_string(), value: b"2".to_vec() });
+        assert_eq!(store.get("h"), Some(&Value::Hash([("f".to_string(), b"2".to_vec())].into_iter().collect())));
+    }
+
+    #[test]
+    fn refuses_operations_for_another_type() {
+        let mut store = KeyValueStore::new();
+        apply(&mut store, "l", Operation::ListPush { front: true, value: b"a".to_vec() });
+        let list = store.get("l").cloned();
+        for operation in [
+            Operation::Increment(1),
+            Operation::CompareAndSwap { expected: b"a".to_vec(), new: b"b".to_vec() },
+            Operation::PutIfAbsent(b"a".to_vec()),
+            Operation::SetAdd(b"a".to_vec()),
+            Operation::SetRemove(b"a".to_vec()),
+            Operation::HashSet { field: "f".to_string(), value: b"a".to_vec() },
+        ] {
+            assert_eq!(apply(&mut store, "l", operation), WriteResult::WrongType("list"));
+        }
+        apply(&mut store, "s", Operation::SetAdd(b"a".to_vec()));
+        assert_eq!(apply(&mut store, "s", Operation::ListPop { front: true }), WriteResult::WrongType("set"));
+        assert_eq!(store.get("l").cloned(), list);
+        //A put or delete replaces a key of any type
+        assert_eq!(apply(&mut store, "l", Operation::Put(b"a".to_vec())), WriteResult::Applied);
+        assert_eq!(store.get("l"), Some(&string("a")));
+        assert_eq!(apply(&mut store, "s", Operation::Delete), WriteResult::Applied);
+        assert_eq!(store.get("s"), None);
+    }
+
+    #[test]
+    fn transactions_check_every_guard_first() {
+        let mut store = KeyValueStore::new();
+        apply(&mut store, "a", Operation::Put(b"10".to_vec()));
+        apply(&mut store, "l", Operation::ListPush { front: true, value: b"10".to_vec() });
+        let guard = |key: &str, condition: Condition| Guard { key: key.to_string(), condition };
+        let writes = vec![TransactionWrite::Put("a".to_string(), b"7".to_vec()), TransactionWrite::Delete("l".to_string()), TransactionWrite::Put("b".to_string(), b"3".to_vec())];
+        //One guard that does not hold keeps every write from taking effect
+        let failing = Operation::Transaction { guards: vec![guard("a", Condition::Equals(b"10".to_vec())), guard("b", Condition::Exists)], writes: writes.clone() };
+        assert_eq!(apply(&mut store, "a", failing), WriteResult::Transaction(false));
+        assert_eq!(store.get("a"), Some(&string("10")));
+        assert!(store.get("l").is_some());
+        //Only a string can equal a value, and a key that does not exist is not equal to any value
+        let list = Operation::Transaction { guards: vec![guard("l", Condition::Equals(b"10".to_vec()))], writes: writes.clone() };
+        assert_eq!(apply(&mut store, "a", list), WriteResult::Transaction(false));
+        let passing = Operation::Transaction {
+            guards: vec![guard("a", Condition::Equals(b"10".to_vec())), guard("l", Condition::NotEquals(b"10".to_vec())), guard("b", Condition::NotEquals(b"3".to_vec())), guard("b", Condition::Absent)],
+            writes,
+        };
+        assert_eq!(apply(&mut store, "a", passing), WriteResult::Transaction(true));
+        assert_eq!(store.get("a"), Some(&string("7")));
+        assert_eq!(store.get("l"), None);
+        assert_eq!(store.get("b"), Some(&string("3")));
+    }
+}
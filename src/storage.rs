@@ -0,0 +1,122 @@
+//Imports
+use crate::{KeyValue, file_storage::FileStorage};
+//OmniPaxos library
+use omnipaxos_core::{
+    ballot_leader_election::Ballot,
+    storage::{memory_storage::MemoryStorage, StopSignEntry, Storage},
+};
+//Used for the data directory
+use std::path::PathBuf;
+
+//The NodeStorage enum is the storage handed to SequencePaxos
+//Nodes started with a data directory persist their state to disk, the others keep it in memory only
+pub enum NodeStorage {
+    Memory(MemoryStorage<KeyValue, ()>),
+    File(FileStorage),
+}
+
+impl NodeStorage {
+    //Open the storage selected by the --data-dir flag
+    pub fn open(data_dir: &Option<PathBuf>) -> NodeStorage {
+        match data_dir {
+            Some(dir) => NodeStorage::File(FileStorage::open(dir)),
+            None => NodeStorage::Memory(MemoryStorage::<KeyValue, ()>::default()),
+        }
+    }
+
+    //Whether the storage holds state from a previous run - memory storage never does
+    pub fn has_state(&self) -> bool {
+        match self {
+            NodeStorage::Memory(_) => false,
+            NodeStorage::File(storage) => storage.has_state(),
+        }
+    }
+}
+
+//Forward a call to whichever storage is in use
+macro_rules! with_storage {
+    ($self:ident, $storage:ident => $call:expr) => {
+        match $self {
+            NodeStorage::Memory($storage) => $call,
+            NodeStorage::File($storage) => $call,
+        }
+    };
+}
+
+impl Storage<KeyValue, ()> for NodeStorage {
+    fn append_entry(&mut self, entry: KeyValue) -> u64 {
+        with_storage!(self, storage => storage.append_entry(entry))
+    }
+
+    fn append_entries(&mut self, entries: Vec<KeyValue>) -> u64 {
+        with_storage!(self, storage => storage.append_entries(entries))
+    }
+
+    fn append_on_prefix(&mut self, from_idx: u64, entries: Vec<KeyValue>) -> u64 {
+        with_storage!(self, storage => storage.append_on_prefix(from_idx, entries))
+    }
+
+    fn set_promise(&mut self, n_prom: Ballot) {
+        with_storage!(self, storage => storage.set_promise(n_prom))
+    }
+
+    fn set_decided_idx(&mut self, ld: u64) {
+        with_storage!(self, storage => storage.set_decided_idx(ld))
+    }
+
+    fn get_decided_idx(&self) -> u64 {
+        with_storage!(self, storage => storage.get_decided_idx())
+    }
+
+    fn set_accepted_round(&mut self, na: Ballot) {
+        with_storage!(self, storage => storage.set_accepted_round(na))
+    }
+
+    fn get_accepted_round(&self) -> Ballot {
+        with_storage!(self, storage => storage.get_accepted_round())
+    }
+
+    fn get_entries(&self, from: u64, to: u64) -> Vec<KeyValue> {
+        with_storage!(self, storage => storage.get_entries(from, to))
+    }
+
+    fn get_log_len(&self) -> u64 {
+        with_storage!(self, storage => storage.get_log_len())
+    }
+
+    fn get_suffix(&self, from: u64) -> Vec<KeyValue> {
+        with_storage!(self, storage => storage.get_suffix(from))
+    }
+
+    fn get_promise(&self) -> Ballot {
+        with_storage!(self, storage => storage.get_promise())
+    }
+
+    fn set_stopsign(&mut self, s: StopSignEntry) {
+        with_storage!(self, storage => storage.set_stopsign(s))
+    }
+
+    fn get_stopsign(&self) -> Option<StopSignEntry> {
+        with_storage!(self, storage => storage.get_stopsign())
+    }
+
+    fn trim(&mut self, idx: u64) {
+        with_storage!(self, storage => storage.trim(idx))
+    }
+
+    fn set_compacted_idx(&mut self, idx: u64) {
+        with_storage!(self, storage => storage.set_compacted_idx(idx))
+    }
+
+    fn get_compacted_idx(&self) -> u64 {
+        with_storage!(self, storage => storage.get_compacted_idx())
+    }
+
+    fn set_snapshot(&mut self, snapshot: ()) {
+        with_storage!(self, storage => storage.set_snapshot(snapshot))
+    }
+
+    fn get_snapshot(&self) -> Option<()> {
+        with_storage!(self, storage => storage.get_snapshot())
+    }
+}
@@ -0,0 +1,12 @@
+//Imports
+//Used for the directories the tests keep their files in
+use std::{fs, path::PathBuf};
+
+//The test_dir function gives a fresh, empty directory for a test - the pid keeps test runs that happen at the same time apart
+//The name has to be unique among all tests that use a directory
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("omnipaxos-kv-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
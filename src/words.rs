@@ -0,0 +1,95 @@
+//Requests and responses are lines of words separated by spaces
+//A word that contains spaces (or is empty) is written in double quotes, with \" and \\ standing for a quote and a backslash inside them
+
+//The split_words function splits a line into its words, removing the quotes
+pub fn split_words(line: &str) -> Result<Vec<String>, String> {
+    let mut words = vec![];
+    let mut characters = line.trim().chars().peekable();
+    while let Some(character) = characters.next() {
+        if character.is_whitespace() {
+            continue;
+        }
+        let mut word = String::new();
+        if character == '"' {
+            loop {
+                match characters.next() {
+                    Some('"') => break,
+                    Some('\\') => match characters.next() {
+                        Some(escaped) => word.push(escaped),
+                        None => return Err("the line ends in the middle of an escape".to_string()),
+                    },
+                    Some(quoted) => word.push(quoted),
+                    None => return Err("a quote is not closed".to_string()),
+                }
+            }
+            //The closing quote has to end the word
+            if matches!(characters.peek(), Some(next) if !next.is_whitespace()) {
+                return Err("a closing quote must be followed by a space".to_string());
+            }
+        } else {
+            word.push(character);
+            while let Some(next) = characters.next_if(|next| !next.is_whitespace()) {
+                word.push(next);
+            }
+        }
+        words.push(word);
+    }
+    Ok(words)
+}
+
+//The quote function writes a word so that split_words reads it back unchanged - in quotes only if it needs them
+pub fn quote(word: &str) -> String {
+    if !word.is_empty() && !word.starts_with('"') && !word.contains(char::is_whitespace) {
+        return word.to_string();
+    }
+    let mut quoted = String::from("\"");
+    for character in word.chars() {
+        if character == '"' || character == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(character);
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quote_round_trip() {
+        let words = ["plain", "", "two words", "\"leading quote", "inner\"quote", "back\\slash", "\\", "\"", "ends in \\", "tab\there", "\"\""];
+        for word in words {
+            assert_eq!(split_words(&quote(word)), Ok(vec![word.to_string()]), "{:?} quoted as {:?}", word, quote(word));
+        }
+        let line = words.iter().map(|word| quote(word)).collect::<Vec<_>>().join(" ");
+        assert_eq!(split_words(&line), Ok(words.iter().map(|word| word.to_string()).collect()));
+    }
+
+    #[test]
+    fn only_quotes_when_needed() {
+        assert_eq!(quote("key"), "key");
+        assert_eq!(quote("back\\slash"), "back\\slash");
+        assert_eq!(quote(""), "\"\"");
+        assert_eq!(quote("two words"), "\"two words\"");
+        assert_eq!(quote("\"key"), "\"\\\"key\"");
+    }
+
+    #[test]
+    fn splits_lines() {
+        assert_eq!(split_words("  put   key  value "), Ok(vec!["put".to_string(), "key".to_string(), "value".to_string()]));
+        assert_eq!(split_words("put \"\" value"), Ok(vec!["put".to_string(), String::new(), "value".to_string()]));
+        assert_eq!(split_words(""), Ok(vec![]));
+        //Quotes and backslashes inside an unquoted word are part of it
+        assert_eq!(split_words("a\"b c\\d"), Ok(vec!["a\"b".to_string(), "c\\d".to_string()]));
+    }
+
+    #[test]
+    fn refuses_broken_quotes() {
+        assert!(split_words("put \"key value").is_err());
+        assert!(split_words("put \"key\\").is_err());
+        assert!(split_words("put \"key\"value").is_err());
+        assert!(split_words("\"").is_err());
+    }
+}